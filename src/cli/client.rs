@@ -1,56 +1,111 @@
 use clap::Parser;
-use std::io::prelude::*;
+use std::io::ErrorKind;
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::path::{self, Path, PathBuf};
+use std::process::exit;
 
+use common::files;
+use common::protocol::{self, ProtocolError, Request, Response};
+use common::{Command, ModeArgs, PlaylistArgs, SourceArgs};
 use log::info;
 
+/// Exit code used when talking to the daemon failed. Errors reported by the
+/// daemon itself use [`common::ErrorKind::exit_code`]
+const EXIT_CONNECTION: i32 = 1;
+
 #[derive(Parser)]
-#[clap(version)]
+#[clap(
+    version,
+    after_help = "EXIT STATUS:\n    0  Success\n    1  Couldn't talk to the daemon\n    2  Invalid argument\n    3  Refused in the current state\n    4  Setting the wallpaper failed\n    5  Reading the wallpaper directory failed\n    6  The daemon didn't understand the request"
+)]
 pub struct Cli {
     /// Socket for communication
     #[clap(short, long, value_parser, value_name = "FILE")]
     socket: Option<PathBuf>,
+    /// Config file to take the socket from if it isn't given [default: $XDG_CONFIG_HOME/wallpaper/config.toml]
+    #[clap(long, value_parser, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Print responses as JSON
+    #[clap(long)]
+    json: bool,
     #[clap(subcommand)]
-    command: common::Command,
+    command: Command,
 }
 
 fn main() {
     pretty_env_logger::init();
 
-    let cli = Cli::parse();
-    let socket = cli.socket.unwrap_or_else(|| {
-        if let Ok(path) = std::env::var("XDG_RUNTIME_DIR") {
-            let mut pathbuf = PathBuf::new();
-            pathbuf.push(path);
-            pathbuf.push("wallpaperd");
-            pathbuf
-        } else {
-            PathBuf::from_str("/tmp/wallpaperd").unwrap()
+    let mut cli = Cli::parse();
+    // The daemon doesn't know which directory `wp` was called from
+    match &mut cli.command {
+        Command::Source(SourceArgs::Add(spec)) => spec.path = absolute(&spec.path),
+        Command::Source(SourceArgs::Remove(dir)) => dir.path = absolute(&dir.path),
+        Command::Playlist(PlaylistArgs::Load(file)) => file.path = absolute(&file.path),
+        Command::Playlist(PlaylistArgs::Save(args)) => args.path = absolute(&args.path),
+        Command::Tag(args) | Command::Untag(args) => {
+            args.path = args.path.as_deref().map(absolute);
+        }
+        Command::Rate(args) => args.path = args.path.as_deref().map(absolute),
+        Command::Fav(image) | Command::Mode(ModeArgs::Static(image)) => {
+            image.path = image.path.as_deref().map(absolute);
         }
-    });
+        Command::Unban(image) => image.path = absolute(&image.path),
+        _ => {}
+    }
+    let socket = cli
+        .socket
+        .unwrap_or_else(|| files::socket_path(&cli.config.unwrap_or_else(files::config_path)));
 
-    let mut socket = UnixStream::connect(socket).expect("Socket not found");
+    let mut socket = match UnixStream::connect(&socket) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Couldn't connect to {}: {e}", socket.to_string_lossy());
+            exit(EXIT_CONNECTION);
+        }
+    };
 
-    //TODO make not shit
-    let args = cli.command.to_string();
+    let request = Request::from(cli.command);
+    info!("Sending {:?}", request);
+    if let Err(e) = protocol::client_handshake(&mut socket)
+        .and_then(|_| protocol::write_message(&mut socket, &request))
+    {
+        eprintln!("{e}");
+        exit(EXIT_CONNECTION);
+    }
 
-    let len = args.len();
+    let subscribed = matches!(request, Request::Subscribe);
+    loop {
+        let response = match protocol::read_message::<_, Response>(&mut socket) {
+            Ok(response) => response,
+            // The daemon closes the stream of events when it exits
+            Err(ProtocolError::Io(e)) if subscribed && e.kind() == ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                exit(EXIT_CONNECTION);
+            }
+        };
 
-    info!("Sending {:?}", args);
-    {
-        socket.write_all(&len.to_ne_bytes()).unwrap();
-        socket.write_all(args.trim().as_bytes()).unwrap();
-        info!("{:?}", &len.to_ne_bytes());
-        info!("{:?}", args.trim().as_bytes());
-        socket.flush().unwrap();
+        match &response {
+            Response::Error(e) => {
+                if cli.json {
+                    eprintln!("{}", response.to_json());
+                } else {
+                    eprintln!("{response}");
+                }
+                exit(e.kind().exit_code());
+            }
+            _ if cli.json => println!("{}", response.to_json()),
+            Response::Ok => {}
+            _ => println!("{response}"),
+        }
+        if !subscribed {
+            break;
+        }
     }
+}
 
-    info!("Reading:");
-    let mut line = String::new();
-    socket
-        .read_to_string(&mut line)
-        .expect("Couldn't read string");
-    println!("{}", line);
+fn absolute(path: &Path) -> PathBuf {
+    path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
 }
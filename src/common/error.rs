@@ -0,0 +1,217 @@
+use std::{fmt::Display, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Category of a failed request, decides the exit code of `wp`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The request couldn't be understood
+    Protocol,
+    /// The request doesn't make sense, e.g. a static image that doesn't exist
+    InvalidArgument,
+    /// The request can't be handled right now, e.g. `next` in static mode
+    Refused,
+    /// The program setting the wallpaper failed
+    Backend,
+    /// Reading from the file system failed
+    Io,
+}
+
+impl ErrorKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Protocol => 6,
+            ErrorKind::InvalidArgument => 2,
+            ErrorKind::Refused => 3,
+            ErrorKind::Backend => 4,
+            ErrorKind::Io => 5,
+        }
+    }
+}
+
+/// Everything that can go wrong in the daemon
+///
+/// None of these are fatal, the daemon reports them to the client that
+/// caused them and carries on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WallpaperError {
+    /// The client sent something that isn't a valid request
+    InvalidRequest(String),
+    NotAFile(PathBuf),
+    ZeroInterval,
+    /// Longer than [`crate::MAX_INTERVAL`]
+    IntervalTooLong,
+    FallbackActive,
+    StaticMode,
+    NoPreviousImage,
+    /// Banning the default image would leave nothing to fall back to
+    BanDefault,
+    NotBanned(PathBuf),
+    NotADirectory(PathBuf),
+    /// The directory already is a source
+    SourceExists(PathBuf),
+    NoSuchSource(PathBuf),
+    /// A glob pattern that can't be parsed
+    InvalidPattern {
+        pattern: String,
+        message: String,
+    },
+    NoSuchPattern(String),
+    /// Unloading while no playlist is loaded
+    NoPlaylist,
+    AlreadyPaused,
+    NotPaused,
+    NoSuchCollection(String),
+    /// Only tags given by hand can be taken away
+    NotTagged(String),
+    /// The config file can't be parsed
+    Config {
+        path: PathBuf,
+        message: String,
+    },
+    /// A schedule rule in the config file doesn't make sense
+    Schedule(String),
+    /// The config file doesn't say where the sun is
+    NoLocation,
+    /// None of the directories contain anything to show
+    NoImages(Vec<PathBuf>),
+    /// Reading a file or directory failed
+    Io {
+        path: PathBuf,
+        message: String,
+    },
+    /// The backend program couldn't be started
+    Spawn {
+        program: String,
+        message: String,
+    },
+    /// The backend program ran but reported a failure
+    ExitStatus {
+        program: String,
+        status: String,
+    },
+    /// Hyprland's environment variable isn't set, so hyprpaper can't be found
+    NoHyprlandInstance,
+    /// Talking to the hyprpaper socket failed
+    HyprpaperIpc(String),
+    /// Hyprpaper answered with something other than "ok"
+    HyprpaperReply(String),
+}
+
+impl WallpaperError {
+    pub fn io(path: impl Into<PathBuf>, e: std::io::Error) -> Self {
+        WallpaperError::Io {
+            path: path.into(),
+            message: e.to_string(),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            WallpaperError::InvalidRequest(_) => ErrorKind::Protocol,
+            WallpaperError::NotAFile(_)
+            | WallpaperError::ZeroInterval
+            | WallpaperError::IntervalTooLong
+            | WallpaperError::BanDefault
+            | WallpaperError::NotBanned(_)
+            | WallpaperError::NotADirectory(_)
+            | WallpaperError::SourceExists(_)
+            | WallpaperError::NoSuchSource(_)
+            | WallpaperError::InvalidPattern { .. }
+            | WallpaperError::NoSuchPattern(_)
+            | WallpaperError::NoSuchCollection(_)
+            | WallpaperError::NotTagged(_)
+            | WallpaperError::Config { .. }
+            | WallpaperError::Schedule(_) => ErrorKind::InvalidArgument,
+            WallpaperError::FallbackActive
+            | WallpaperError::StaticMode
+            | WallpaperError::NoPreviousImage
+            | WallpaperError::NoPlaylist
+            | WallpaperError::AlreadyPaused
+            | WallpaperError::NotPaused
+            | WallpaperError::NoLocation
+            | WallpaperError::NoImages(_) => ErrorKind::Refused,
+            WallpaperError::Io { .. } => ErrorKind::Io,
+            WallpaperError::Spawn { .. }
+            | WallpaperError::ExitStatus { .. }
+            | WallpaperError::NoHyprlandInstance
+            | WallpaperError::HyprpaperIpc(_)
+            | WallpaperError::HyprpaperReply(_) => ErrorKind::Backend,
+        }
+    }
+}
+
+impl Display for WallpaperError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WallpaperError::InvalidRequest(e) => write!(f, "Invalid request: {e}"),
+            WallpaperError::NotAFile(path) => {
+                write!(f, "{} is not a file", path.to_string_lossy())
+            }
+            WallpaperError::ZeroInterval => write!(f, "The interval must be at least one second"),
+            WallpaperError::IntervalTooLong => write!(
+                f,
+                "The interval can be at most {} seconds",
+                crate::MAX_INTERVAL.as_secs()
+            ),
+            WallpaperError::FallbackActive => write!(f, "Can't change image while using fallback"),
+            WallpaperError::StaticMode => write!(f, "Can't change image while in static mode"),
+            WallpaperError::NoPreviousImage => write!(f, "There is no previous image"),
+            WallpaperError::BanDefault => write!(f, "The default image can't be banned"),
+            WallpaperError::NotBanned(path) => {
+                write!(f, "{} is not banned", path.to_string_lossy())
+            }
+            WallpaperError::NotADirectory(path) => {
+                write!(f, "{} is not a directory", path.to_string_lossy())
+            }
+            WallpaperError::SourceExists(path) => {
+                write!(f, "{} already is a source", path.to_string_lossy())
+            }
+            WallpaperError::NoSuchSource(path) => {
+                write!(f, "{} is not a source", path.to_string_lossy())
+            }
+            WallpaperError::InvalidPattern { pattern, message } => {
+                write!(f, "Invalid pattern {pattern}: {message}")
+            }
+            WallpaperError::NoSuchPattern(pattern) => write!(f, "There is no pattern {pattern}"),
+            WallpaperError::NoPlaylist => write!(f, "No playlist is loaded"),
+            WallpaperError::AlreadyPaused => write!(f, "The rotation is already paused"),
+            WallpaperError::NotPaused => write!(f, "The rotation isn't paused"),
+            WallpaperError::NoSuchCollection(name) => {
+                write!(f, "No image belongs to the collection {name}")
+            }
+            WallpaperError::NotTagged(tag) => write!(f, "The image isn't tagged {tag}"),
+            WallpaperError::Config { path, message } => {
+                write!(f, "Invalid config {}: {message}", path.to_string_lossy())
+            }
+            WallpaperError::Schedule(message) => write!(f, "Invalid schedule: {message}"),
+            WallpaperError::NoLocation => {
+                write!(f, "No latitude and longitude are configured")
+            }
+            WallpaperError::NoImages(dirs) if dirs.is_empty() => {
+                write!(f, "There are no directories to search for images")
+            }
+            WallpaperError::NoImages(dirs) => {
+                let dirs: Vec<_> = dirs.iter().map(|dir| dir.to_string_lossy()).collect();
+                write!(f, "No images found in {}", dirs.join(", "))
+            }
+            WallpaperError::Io { path, message } => {
+                write!(f, "Couldn't read {}: {message}", path.to_string_lossy())
+            }
+            WallpaperError::Spawn { program, message } => {
+                write!(f, "Couldn't run {program}: {message}")
+            }
+            WallpaperError::ExitStatus { program, status } => {
+                write!(f, "{program} failed ({status})")
+            }
+            WallpaperError::NoHyprlandInstance => {
+                write!(f, "HYPRLAND_INSTANCE_SIGNATURE is not set")
+            }
+            WallpaperError::HyprpaperIpc(e) => write!(f, "Couldn't talk to hyprpaper: {e}"),
+            WallpaperError::HyprpaperReply(reply) => write!(f, "hyprpaper: {reply}"),
+        }
+    }
+}
+
+impl std::error::Error for WallpaperError {}
@@ -0,0 +1,62 @@
+//! Where the daemon keeps its files and how it writes them
+
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::WallpaperError;
+
+/// `path` inside the XDG base directory in `$var`, or in `$HOME/fallback`
+/// if the variable isn't set
+pub fn xdg_path(var: &str, fallback: &str, path: impl AsRef<Path>) -> PathBuf {
+    let base = match std::env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(fallback),
+    };
+    base.join(path)
+}
+
+/// `$XDG_CONFIG_HOME/wallpaper/config.toml`
+pub fn config_path() -> PathBuf {
+    xdg_path("XDG_CONFIG_HOME", ".config", "wallpaper/config.toml")
+}
+
+/// `$XDG_RUNTIME_DIR/wallpaperd`
+pub fn default_socket() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("wallpaperd"),
+        None => PathBuf::from("/tmp/wallpaperd"),
+    }
+}
+
+/// The socket set in the config file at `config`, or the default one
+///
+/// Only the `socket` key is read, the daemon reports problems with the rest.
+pub fn socket_path(config: &Path) -> PathBuf {
+    #[derive(Deserialize)]
+    struct SocketConfig {
+        socket: Option<PathBuf>,
+    }
+
+    fs::read_to_string(config)
+        .ok()
+        .and_then(|content| toml::from_str::<SocketConfig>(&content).ok())
+        .and_then(|config| config.socket)
+        .unwrap_or_else(default_socket)
+}
+
+/// Replaces the file at `path`, creating its directory if needed
+///
+/// The content goes to a temporary file first, so a crash can't leave half
+/// a file behind.
+pub fn write_atomic(path: &Path, content: &str) -> Result<(), WallpaperError> {
+    let io_error = |e| WallpaperError::io(path, e);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content).map_err(io_error)?;
+    fs::rename(&tmp, path).map_err(io_error)
+}
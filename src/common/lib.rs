@@ -1,8 +1,16 @@
-use std::{fmt::Display, path::PathBuf, time::Duration};
+use std::{fmt::Display, path::PathBuf, str::FromStr, time::Duration};
 
-use clap::{Args, Subcommand};
+use clap::{ArgEnum, Args, Subcommand};
+use serde::{Deserialize, Serialize};
 
-#[derive(Subcommand)]
+pub mod error;
+pub mod files;
+pub mod protocol;
+
+pub use error::{ErrorKind, WallpaperError};
+
+/// The commands of `wp`, sent to the daemon as a [`protocol::Request`]
+#[derive(Subcommand, Debug)]
 pub enum Command {
     /// Show the next image
     Next,
@@ -18,68 +26,610 @@ pub enum Command {
     Fallback,
     /// Set the interval for new images in seconds
     Interval(IntervalDuration),
+    /// Stop the timer from changing the image, keeping the mode
+    Pause,
+    /// Let the timer continue where it was paused
+    Resume,
+    /// Pause the timer, or resume it if it already is paused
+    TogglePause,
     /// Query information about the current state
     #[clap(subcommand)]
     Get(GetArgs),
+    /// Search the wallpaper directories for images again
+    Rescan,
+    /// Change the directories images are picked from
+    #[clap(subcommand)]
+    Source(SourceArgs),
+    /// Change which images of the directories are shown
+    #[clap(subcommand)]
+    Filter(FilterArgs),
+    /// Show the images of a playlist file instead of the directories
+    #[clap(subcommand)]
+    Playlist(PlaylistArgs),
+    /// Set the order linear mode walks through the images
+    Sort(SortOrder),
+    /// Rate an image, random mode shows higher rated images more often
+    Rate(RateArgs),
+    /// Mark an image as favourite, or unmark it if it already is one
+    Fav(Image),
+    /// Add an image to a collection
+    Tag(TagArgs),
+    /// Take an image out of a collection again
+    Untag(TagArgs),
+    /// Only show the images of one collection
+    #[clap(subcommand)]
+    Collection(CollectionArgs),
+    /// Never show the current image again and skip to the next one
+    Ban,
+    /// Allow a banned image to be shown again
+    Unban(ImagePath),
+    /// List things the daemon knows about
+    #[clap(subcommand)]
+    List(ListArgs),
+    /// Show what the schedule in the config file does
+    #[clap(subcommand)]
+    Schedule(ScheduleArgs),
+    /// Read the config file again and apply what changed
+    Reload,
+    /// Keep the connection open and print an event whenever the state changes
+    Subscribe,
 }
 
-#[derive(Args)]
+/// The longest interval the timer accepts, a year
+pub const MAX_INTERVAL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+#[derive(Args, Serialize, Deserialize, Debug)]
 pub struct IntervalDuration {
     #[clap(parse(try_from_str = parse_duration))]
     pub duration: Duration,
 }
 
-#[derive(Subcommand)]
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
 pub enum ModeArgs {
     Linear,
     Random,
+    /// Random order, but every image is shown once before any repeats
+    Shuffle,
     Static(Image),
 }
 
-#[derive(Args)]
+#[derive(Args, Serialize, Deserialize, Debug)]
 pub struct Image {
     pub path: Option<PathBuf>,
 }
 
-#[derive(Subcommand)]
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct ImagePath {
+    pub path: PathBuf,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct RateArgs {
+    /// From 1 to 5
+    #[clap(value_parser = clap::value_parser!(u8).range(1..=5))]
+    pub stars: u8,
+    /// Image to rate, the current one if not given
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct TagArgs {
+    pub tag: String,
+    /// Image to tag, the current one if not given
+    pub path: Option<PathBuf>,
+}
+
+/// Images belong to the collections they were tagged with and to the ones
+/// named after the directories they are in
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+pub enum CollectionArgs {
+    /// Switch to a collection, resuming its history
+    Use(CollectionName),
+    /// Show all images again
+    Clear,
+    /// Show the collections and how many images they have
+    List,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct CollectionName {
+    pub name: String,
+}
+
+/// A collection as listed by `collection list`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collection {
+    pub name: String,
+    pub images: usize,
+    /// Whether it is the one in use
+    pub active: bool,
+}
+
+impl Display for Collection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let marker = if self.active { '*' } else { ' ' };
+        write!(f, "{marker} {} ({})", self.name, self.images)
+    }
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
 pub enum GetArgs {
     Wallpaper,
     Duration,
+    /// How the next image is chosen and which schedule rules decided that
     Mode,
     Fallback,
+    /// Whether the timer is paused
+    Paused,
+    /// Seconds until the timer changes the image
+    Remaining,
+    Sort,
+    /// Rating of the current image
+    Rating,
+    /// Collections the current image belongs to
+    Tags,
+    /// Today's sunrise and sunset at the configured location
+    Sun,
+    /// Whether the images are rotating and if not, why
+    Status,
+    /// Everything at once
+    State,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+pub enum SourceArgs {
+    /// Pick images from another directory as well
+    Add(SourceSpec),
+    /// Stop picking images from a directory
+    Remove(ImagePath),
+    /// Show the directories images are picked from
+    List,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+pub enum PlaylistArgs {
+    /// Show the images listed in a file, one path per line, in that order
+    Load(ImagePath),
+    /// Write the order the images are shown in to a file
+    Save(SavePlaylist),
+    /// Go back to the images in the directories
+    Unload,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct SavePlaylist {
+    pub path: PathBuf,
+    /// Write the images that were shown instead, oldest first
+    #[clap(long)]
+    pub history: bool,
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+pub enum FilterArgs {
+    /// Only show images matching this or another include pattern
+    Include(PatternArg),
+    /// Never show images matching the pattern
+    Exclude(PatternArg),
+    /// Drop an include or exclude pattern
+    Remove(PatternArg),
+    /// Show the patterns in use
+    List,
+}
+
+#[derive(Args, Serialize, Deserialize, Debug)]
+pub struct PatternArg {
+    /// Glob matched against the path relative to the wallpaper directory,
+    /// e.g. `drafts/`, `*-raw.*` or `work/**/*.png`
+    pub pattern: String,
 }
 
-fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
-    let seconds = arg.parse()?;
-    Ok(std::time::Duration::from_secs(seconds))
+/// Include and exclude patterns deciding which images are shown
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Filters {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
 }
 
-impl Display for Command {
+impl Display for Filters {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let args = match self {
-            Command::Next => "next".to_string(),
-            Command::Stop => "stop".to_string(),
-            Command::Previous => "previous".to_string(),
-            Command::Mode(mode) => match mode {
-                ModeArgs::Linear => "mode linear".to_string(),
-                ModeArgs::Random => "mode random".to_string(),
-                ModeArgs::Static(img) => {
-                    if let Some(path) = &img.path {
-                        format!("mode static {}", path.to_string_lossy())
-                    } else {
-                        "mode static".to_string()
-                    }
+        let include = self
+            .include
+            .iter()
+            .map(|pattern| format!("include {pattern}"));
+        let exclude = self
+            .exclude
+            .iter()
+            .map(|pattern| format!("exclude {pattern}"));
+        let lines: Vec<_> = include.chain(exclude).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// A directory to pick images from
+///
+/// Settings that aren't given fall back to the ones the daemon was started
+/// with. On the daemon's command line a directory can carry its own settings
+/// after a colon, e.g. `~/pictures:recursive,max-depth=2,extensions=png+jpg`.
+/// The `no-` options turn a setting off for one directory.
+#[derive(Args, Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceSpec {
+    pub path: PathBuf,
+    /// Search for images recursively, `--recursive=false` to turn it off
+    #[clap(
+        short,
+        long,
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub recursive: Option<bool>,
+    /// How many levels of subdirectories to search
+    #[clap(long, value_name = "DEPTH", requires = "recursive")]
+    pub max_depth: Option<usize>,
+    /// Also use files and directories starting with a dot
+    #[clap(
+        long,
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub hidden: Option<bool>,
+    /// File extensions considered to be images
+    #[clap(long, value_name = "EXTENSIONS", value_delimiter = ',')]
+    pub extensions: Option<Vec<String>>,
+    /// Check the content of each file instead of trusting file extensions,
+    /// `--sniff=false` to turn it off
+    #[clap(
+        long,
+        value_name = "BOOL",
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub sniff: Option<bool>,
+}
+
+impl FromStr for SourceSpec {
+    type Err = String;
+
+    /// Parses `DIRECTORY[:OPTION,...]`, a path containing a colon can be
+    /// given with an empty option list, e.g. `a:b:`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, options) = match s.rsplit_once(':') {
+            Some((path, options)) => (path, options),
+            None => (s, ""),
+        };
+        let mut spec = SourceSpec {
+            path: PathBuf::from(path),
+            ..Default::default()
+        };
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                None if option == "recursive" => spec.recursive = Some(true),
+                None if option == "no-recursive" => spec.recursive = Some(false),
+                None if option == "hidden" => spec.hidden = Some(true),
+                None if option == "no-hidden" => spec.hidden = Some(false),
+                None if option == "sniff" => spec.sniff = Some(true),
+                None if option == "no-sniff" => spec.sniff = Some(false),
+                Some(("max-depth", depth)) => {
+                    let depth = depth
+                        .parse()
+                        .map_err(|e| format!("invalid max-depth {depth:?}: {e}"))?;
+                    spec.recursive.get_or_insert(true);
+                    spec.max_depth = Some(depth);
                 }
-            },
-            Command::Fallback => "fallback".to_string(),
-            Command::Interval(dur) => format!("interval {}", dur.duration.as_secs()),
-            Command::Get(what) => match what {
-                GetArgs::Wallpaper => "get wallpaper".to_string(),
-                GetArgs::Duration => "get duration".to_string(),
-                GetArgs::Mode => "get mode".to_string(),
-                GetArgs::Fallback => "get fallback".to_string(),
-            },
+                Some(("extensions", extensions)) => {
+                    spec.extensions = Some(extensions.split('+').map(String::from).collect());
+                }
+                _ => return Err(format!("unknown directory option {option:?}")),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// The inverse of [`SourceSpec::from_str`]
+impl Display for SourceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |name: &str, value: bool| {
+            if value {
+                name.to_string()
+            } else {
+                format!("no-{name}")
+            }
+        };
+        let mut options = Vec::new();
+        // `max-depth` implies `recursive`
+        match self.recursive {
+            Some(true) if self.max_depth.is_some() => {}
+            Some(recursive) => options.push(flag("recursive", recursive)),
+            None => {}
+        }
+        if let Some(depth) = self.max_depth {
+            options.push(format!("max-depth={depth}"));
+        }
+        if let Some(hidden) = self.hidden {
+            options.push(flag("hidden", hidden));
+        }
+        if let Some(extensions) = &self.extensions {
+            options.push(format!("extensions={}", extensions.join("+")));
+        }
+        if let Some(sniff) = self.sniff {
+            options.push(flag("sniff", sniff));
+        }
+
+        let path = self.path.to_string_lossy();
+        if options.is_empty() && !path.contains(':') {
+            write!(f, "{path}")
+        } else {
+            write!(f, "{path}:{}", options.join(","))
+        }
+    }
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+pub enum ScheduleArgs {
+    /// Show when the next rules start and end
+    List,
+}
+
+/// Sunrise and sunset in local time, `None` if the sun doesn't rise or set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SunTimes {
+    pub sunrise: Option<String>,
+    pub sunset: Option<String>,
+    pub night: bool,
+}
+
+impl Display for SunTimes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "sunrise: {}", self.sunrise.as_deref().unwrap_or("none"))?;
+        writeln!(f, "sunset: {}", self.sunset.as_deref().unwrap_or("none"))?;
+        write!(f, "night: {}", self.night)
+    }
+}
+
+/// A schedule rule starting or ending
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transition {
+    /// Local time, e.g. `Mon 2022-08-01 09:00`
+    pub time: String,
+    /// Seconds from now
+    pub in_secs: u64,
+    pub rule: String,
+    /// `false` if the rule ends
+    pub start: bool,
+}
+
+impl Display for Transition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = if self.start { "starts" } else { "ends" };
+        write!(f, "{}  {} {what}", self.time, self.rule)
+    }
+}
+
+/// A schedule rule currently changing the settings
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActiveRule {
+    pub name: String,
+    /// `None` for the day and night collections, which always come first
+    pub priority: Option<i64>,
+    /// What the rule changes, e.g. `collection winter`
+    pub changes: Vec<String>,
+}
+
+impl Display for ActiveRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(priority) = self.priority {
+            write!(f, " (priority {priority})")?;
+        }
+        write!(f, ": {}", self.changes.join(", "))
+    }
+}
+
+#[derive(Subcommand, Serialize, Deserialize, Debug)]
+pub enum ListArgs {
+    /// Images that were banned
+    Banned,
+}
+
+/// How the next image gets chosen
+#[derive(Debug, Clone, PartialEq, Eq, Copy, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NextImage {
+    Random,
+    Shuffle,
+    Linear,
+    Static,
+}
+
+/// What linear mode sorts the images by
+#[derive(Debug, Clone, PartialEq, Eq, Copy, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    /// File name, with numbers compared by value
+    Name,
+    /// Modification time
+    Mtime,
+    /// Status change time
+    Ctime,
+    /// File size
+    Size,
+}
+
+#[derive(Args, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SortOrder {
+    #[clap(arg_enum)]
+    pub key: SortKey,
+    /// Sort in descending order
+    #[clap(short, long)]
+    pub reverse: bool,
+}
+
+impl Display for SortOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let key = match self.key {
+            SortKey::Name => "name",
+            SortKey::Mtime => "mtime",
+            SortKey::Ctime => "ctime",
+            SortKey::Size => "size",
         };
-        write!(f, "{args}")
+        if self.reverse {
+            write!(f, "{key} reversed")
+        } else {
+            write!(f, "{key}")
+        }
+    }
+}
+
+/// How much an image is liked
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rating {
+    /// From 1 to 5, `None` if the image wasn't rated
+    pub stars: Option<u8>,
+    pub favourite: bool,
+}
+
+impl Display for Rating {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.stars {
+            Some(stars) => write!(f, "{stars}/5")?,
+            None => write!(f, "unrated")?,
+        }
+        if self.favourite {
+            write!(f, ", favourite")?;
+        }
+        Ok(())
+    }
+}
+
+/// What the daemon is currently doing
+#[derive(Debug, Clone, PartialEq, Eq, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Images change with every interval
+    Rotating,
+    /// The mode is static
+    Static,
+    /// The fallback image is shown
+    Fallback,
+    /// The timer was paused
+    Paused,
+    /// The wallpaper directory has no images, the default image is shown
+    NoImages,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Rotating => write!(f, "rotating"),
+            Status::Static => write!(f, "static"),
+            Status::Fallback => write!(f, "fallback"),
+            Status::Paused => write!(f, "paused"),
+            Status::NoImages => write!(f, "no images"),
+        }
+    }
+}
+
+/// Snapshot of the daemon state, answered to `get state`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateSnapshot {
+    pub wallpaper: PathBuf,
+    pub mode: NextImage,
+    pub status: Status,
+    pub sort: SortOrder,
+    pub interval: Duration,
+    pub fallback: bool,
+    pub paused: bool,
+    /// Number of images that can be reached with `previous`
+    pub history_depth: usize,
+    /// Number of images to choose from
+    pub images: usize,
+    /// Time until the timer changes the image, `None` if it won't
+    pub next_change: Option<Duration>,
+    /// Name of the program used to set the wallpaper
+    pub backend: String,
+    /// Playlist the images come from, `None` if they come from directories
+    pub playlist: Option<PathBuf>,
+    /// Collection in use, `None` if all images are shown
+    pub collection: Option<String>,
+}
+
+/// Change of the daemon state, pushed to subscribed clients
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Event {
+    Wallpaper(PathBuf),
+    Mode(NextImage),
+    Fallback(bool),
+    Paused(bool),
+    Interval(Duration),
+}
+
+/// Parses an interval in seconds
+pub fn parse_duration(arg: &str) -> Result<Duration, String> {
+    let seconds = arg.parse().map_err(|e| format!("{e}"))?;
+    let duration = Duration::from_secs(seconds);
+    if duration > MAX_INTERVAL {
+        return Err(WallpaperError::IntervalTooLong.to_string());
+    }
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(text: &str) -> SourceSpec {
+        let spec = SourceSpec::from_str(text).unwrap();
+        assert_eq!(spec.to_string(), text);
+        assert_eq!(SourceSpec::from_str(&spec.to_string()).unwrap(), spec);
+        spec
+    }
+
+    #[test]
+    fn plain_directory() {
+        let spec = round_trip("/pictures");
+        assert_eq!(spec.path, PathBuf::from("/pictures"));
+        assert_eq!(spec.recursive, None);
+    }
+
+    #[test]
+    fn options() {
+        let spec = round_trip("/pictures:recursive,hidden,extensions=png+jpg,no-sniff");
+        assert_eq!(spec.recursive, Some(true));
+        assert_eq!(spec.hidden, Some(true));
+        assert_eq!(
+            spec.extensions,
+            Some(vec!["png".to_string(), "jpg".to_string()])
+        );
+        assert_eq!(spec.sniff, Some(false));
+        round_trip("/pictures:no-recursive,no-hidden,sniff");
+    }
+
+    #[test]
+    fn max_depth_implies_recursive() {
+        let spec = round_trip("/pictures:max-depth=2");
+        assert_eq!(spec.recursive, Some(true));
+        assert_eq!(spec.max_depth, Some(2));
+        let spec = SourceSpec::from_str("/pictures:recursive,max-depth=2").unwrap();
+        assert_eq!(spec.to_string(), "/pictures:max-depth=2");
+        let spec = round_trip("/pictures:no-recursive,max-depth=2");
+        assert_eq!(spec.recursive, Some(false));
+    }
+
+    #[test]
+    fn colon_in_path() {
+        let spec = round_trip("/a:b:");
+        assert_eq!(spec.path, PathBuf::from("/a:b"));
+        round_trip("/a:b:hidden");
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert!(SourceSpec::from_str("/pictures:deep").is_err());
+        assert!(SourceSpec::from_str("/pictures:max-depth=x").is_err());
     }
 }
@@ -0,0 +1,545 @@
+//! Wire protocol spoken between `wp` and `wallpaperd`
+//!
+//! Every connection starts with a handshake: the client sends [`MAGIC`]
+//! followed by its [`PROTOCOL_VERSION`] as a little-endian `u32` and the
+//! daemon answers with its own version. After that both sides exchange
+//! frames, each made of a little-endian `u32` payload length followed by the
+//! bincode encoded [`Request`] or [`Response`].
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    ActiveRule, Collection, CollectionArgs, Command, Event, FilterArgs, Filters, GetArgs, Image,
+    ImagePath, IntervalDuration, ListArgs, ModeArgs, NextImage, PlaylistArgs, RateArgs, Rating,
+    ScheduleArgs, SortOrder, SourceArgs, SourceSpec, StateSnapshot, Status, SunTimes, TagArgs,
+    Transition, WallpaperError,
+};
+
+/// Bytes that open every connection
+pub const MAGIC: [u8; 4] = *b"WPPR";
+/// Bumped whenever [`Request`] or [`Response`] change incompatibly
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Upper bound for a single frame, protects the daemon from bogus lengths
+pub const MAX_FRAME_SIZE: u32 = 1 << 20;
+
+/// Message sent from the client to the daemon
+///
+/// Bincode encodes variants by their index, so new ones are only ever
+/// appended and existing ones never change. Anything else needs a new
+/// [`PROTOCOL_VERSION`].
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    Next,
+    Stop,
+    Previous,
+    Mode(ModeArgs),
+    Fallback,
+    Interval(IntervalDuration),
+    Pause,
+    Resume,
+    TogglePause,
+    Get(GetArgs),
+    Rescan,
+    Source(SourceArgs),
+    Filter(FilterArgs),
+    Playlist(PlaylistArgs),
+    Sort(SortOrder),
+    Rate(RateArgs),
+    Fav(Image),
+    Tag(TagArgs),
+    Untag(TagArgs),
+    Collection(CollectionArgs),
+    Ban,
+    Unban(ImagePath),
+    List(ListArgs),
+    Schedule(ScheduleArgs),
+    Reload,
+    Subscribe,
+}
+
+impl From<Command> for Request {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Next => Request::Next,
+            Command::Stop => Request::Stop,
+            Command::Previous => Request::Previous,
+            Command::Mode(args) => Request::Mode(args),
+            Command::Fallback => Request::Fallback,
+            Command::Interval(args) => Request::Interval(args),
+            Command::Pause => Request::Pause,
+            Command::Resume => Request::Resume,
+            Command::TogglePause => Request::TogglePause,
+            Command::Get(args) => Request::Get(args),
+            Command::Rescan => Request::Rescan,
+            Command::Source(args) => Request::Source(args),
+            Command::Filter(args) => Request::Filter(args),
+            Command::Playlist(args) => Request::Playlist(args),
+            Command::Sort(args) => Request::Sort(args),
+            Command::Rate(args) => Request::Rate(args),
+            Command::Fav(args) => Request::Fav(args),
+            Command::Tag(args) => Request::Tag(args),
+            Command::Untag(args) => Request::Untag(args),
+            Command::Collection(args) => Request::Collection(args),
+            Command::Ban => Request::Ban,
+            Command::Unban(args) => Request::Unban(args),
+            Command::List(args) => Request::List(args),
+            Command::Schedule(args) => Request::Schedule(args),
+            Command::Reload => Request::Reload,
+            Command::Subscribe => Request::Subscribe,
+        }
+    }
+}
+
+/// Message sent from the daemon to the client
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// The request was handled and there is nothing to report
+    Ok,
+    Wallpaper(PathBuf),
+    Duration(Duration),
+    /// Time until the timer changes the image, `None` if it won't
+    Remaining(Option<Duration>),
+    /// The mode and the rules that set it, the one that wins first
+    Mode(NextImage, Vec<ActiveRule>),
+    Fallback(bool),
+    Paused(bool),
+    Status(Status),
+    Sort(SortOrder),
+    Rating(Rating),
+    Paths(Vec<PathBuf>),
+    Sources(Vec<SourceSpec>),
+    Filters(Filters),
+    Tags(Vec<String>),
+    Collections(Vec<Collection>),
+    Transitions(Vec<Transition>),
+    Sun(SunTimes),
+    State(StateSnapshot),
+    /// Number of images found by a rescan
+    ImageCount(usize),
+    /// Sent repeatedly after a `Subscribe` request
+    Event(Event),
+    /// The request couldn't be carried out
+    Error(WallpaperError),
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Ok => Ok(()),
+            Response::Wallpaper(path) => write!(f, "{}", path.to_string_lossy()),
+            Response::Duration(duration) => write!(f, "{}", duration.as_secs()),
+            Response::Mode(mode, rules) => {
+                write!(f, "{mode:?}")?;
+                for rule in rules {
+                    write!(f, "\nset by {rule}")?;
+                }
+                Ok(())
+            }
+            Response::Remaining(Some(remaining)) => write!(f, "{}", remaining.as_secs()),
+            Response::Remaining(None) => write!(f, "never"),
+            Response::Fallback(fallback) => write!(f, "{fallback}"),
+            Response::Paused(paused) => write!(f, "{paused}"),
+            Response::Status(status) => write!(f, "{status}"),
+            Response::Sort(order) => write!(f, "{order}"),
+            Response::Rating(rating) => write!(f, "{rating}"),
+            Response::Paths(paths) => {
+                let lines: Vec<_> = paths.iter().map(|path| path.to_string_lossy()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::Sources(sources) => {
+                let lines: Vec<_> = sources.iter().map(|source| source.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::Filters(filters) => write!(f, "{filters}"),
+            Response::Tags(tags) => write!(f, "{}", tags.join("\n")),
+            Response::Collections(collections) => {
+                let lines: Vec<_> = collections.iter().map(|c| c.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::Sun(sun) => write!(f, "{sun}"),
+            Response::Transitions(transitions) => {
+                let lines: Vec<_> = transitions.iter().map(|t| t.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Response::State(state) => {
+                writeln!(f, "wallpaper: {}", state.wallpaper.to_string_lossy())?;
+                writeln!(f, "mode: {:?}", state.mode)?;
+                writeln!(f, "status: {}", state.status)?;
+                writeln!(f, "sort: {}", state.sort)?;
+                writeln!(f, "interval: {}", state.interval.as_secs())?;
+                writeln!(f, "fallback: {}", state.fallback)?;
+                writeln!(f, "paused: {}", state.paused)?;
+                writeln!(f, "history: {}", state.history_depth)?;
+                writeln!(f, "images: {}", state.images)?;
+                match state.next_change {
+                    Some(next) => writeln!(f, "next change: {}", next.as_secs())?,
+                    None => writeln!(f, "next change: never")?,
+                }
+                writeln!(f, "backend: {}", state.backend)?;
+                match &state.playlist {
+                    Some(playlist) => writeln!(f, "playlist: {}", playlist.to_string_lossy())?,
+                    None => writeln!(f, "playlist: none")?,
+                }
+                match &state.collection {
+                    Some(collection) => write!(f, "collection: {collection}"),
+                    None => write!(f, "collection: none"),
+                }
+            }
+            Response::ImageCount(count) => write!(f, "{count} images"),
+            Response::Error(e) => write!(f, "{e}"),
+            Response::Event(event) => match event {
+                Event::Wallpaper(path) => write!(f, "wallpaper {}", path.to_string_lossy()),
+                Event::Mode(mode) => write!(f, "mode {mode:?}"),
+                Event::Fallback(fallback) => write!(f, "fallback {fallback}"),
+                Event::Paused(paused) => write!(f, "paused {paused}"),
+                Event::Interval(duration) => write!(f, "interval {}", duration.as_secs()),
+            },
+        }
+    }
+}
+
+impl Response {
+    /// Structured representation for scripts and status bars
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Response::Ok => json!({}),
+            Response::Wallpaper(path) => json!({ "wallpaper": lossy(path) }),
+            Response::Duration(duration) => json!({ "interval": duration.as_secs() }),
+            Response::Mode(mode, rules) => json!({ "mode": mode, "rules": rules }),
+            Response::Remaining(remaining) => {
+                json!({ "remaining": remaining.map(|remaining| remaining.as_secs()) })
+            }
+            Response::Fallback(fallback) => json!({ "fallback": fallback }),
+            Response::Paused(paused) => json!({ "paused": paused }),
+            Response::Status(status) => json!({ "status": status }),
+            Response::Sort(order) => json!({ "sort": order }),
+            Response::Rating(rating) => json!({ "rating": rating }),
+            Response::Paths(paths) => {
+                json!({ "paths": paths.iter().map(|path| lossy(path)).collect::<Vec<_>>() })
+            }
+            Response::Sources(sources) => {
+                let sources: Vec<_> = sources
+                    .iter()
+                    .map(|source| SourceSpec {
+                        path: lossy(&source.path).into(),
+                        ..source.clone()
+                    })
+                    .collect();
+                json!({ "sources": sources })
+            }
+            Response::Filters(filters) => json!(filters),
+            Response::Tags(tags) => json!({ "tags": tags }),
+            Response::Collections(collections) => json!({ "collections": collections }),
+            Response::Transitions(transitions) => json!({ "transitions": transitions }),
+            Response::Sun(sun) => json!(sun),
+            Response::State(state) => json!({
+                "wallpaper": lossy(&state.wallpaper),
+                "mode": state.mode,
+                "status": state.status,
+                "sort": state.sort,
+                "interval": state.interval.as_secs(),
+                "fallback": state.fallback,
+                "paused": state.paused,
+                "history_depth": state.history_depth,
+                "images": state.images,
+                "next_change": state.next_change.map(|next| next.as_secs()),
+                "backend": state.backend,
+                "playlist": state.playlist.as_deref().map(lossy),
+                "collection": state.collection,
+            }),
+            Response::ImageCount(count) => json!({ "images": count }),
+            Response::Error(e) => json!({ "error": e.kind(), "message": e.to_string() }),
+            Response::Event(event) => match event {
+                Event::Wallpaper(path) => {
+                    json!({ "event": "wallpaper", "wallpaper": lossy(path) })
+                }
+                Event::Mode(mode) => json!({ "event": "mode", "mode": mode }),
+                Event::Fallback(fallback) => json!({ "event": "fallback", "fallback": fallback }),
+                Event::Paused(paused) => json!({ "event": "paused", "paused": paused }),
+                Event::Interval(duration) => {
+                    json!({ "event": "interval", "interval": duration.as_secs() })
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Io(io::Error),
+    /// The peer didn't open the connection with [`MAGIC`]
+    BadMagic,
+    VersionMismatch {
+        ours: u32,
+        theirs: u32,
+    },
+    FrameTooLarge(usize),
+    Encoding(bincode::Error),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "I/O error: {e}"),
+            ProtocolError::BadMagic => write!(f, "peer doesn't speak the wallpaper protocol"),
+            ProtocolError::VersionMismatch { ours, theirs } => write!(
+                f,
+                "protocol version mismatch (ours: {ours}, theirs: {theirs})"
+            ),
+            ProtocolError::FrameTooLarge(len) => {
+                write!(
+                    f,
+                    "frame of {len} bytes exceeds the maximum of {MAX_FRAME_SIZE}"
+                )
+            }
+            ProtocolError::Encoding(e) => write!(f, "malformed message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for ProtocolError {
+    fn from(e: bincode::Error) -> Self {
+        ProtocolError::Encoding(e)
+    }
+}
+
+/// `json!` can't take paths that aren't UTF-8
+fn lossy(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn codec() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .with_limit(MAX_FRAME_SIZE as u64)
+}
+
+/// Opens a connection from the client side
+pub fn client_handshake<S: Read + Write>(stream: &mut S) -> Result<(), ProtocolError> {
+    stream.write_all(&MAGIC)?;
+    stream.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+    stream.flush()?;
+
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    let theirs = u32::from_le_bytes(buf);
+    if theirs != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch {
+            ours: PROTOCOL_VERSION,
+            theirs,
+        });
+    }
+    Ok(())
+}
+
+/// Accepts a connection on the daemon side
+///
+/// The daemon always answers with its own version so the client can report
+/// a mismatch, even if the connection gets dropped afterwards.
+pub fn server_handshake<S: Read + Write>(stream: &mut S) -> Result<(), ProtocolError> {
+    let mut magic = [0; 4];
+    stream.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(ProtocolError::BadMagic);
+    }
+
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    let theirs = u32::from_le_bytes(buf);
+
+    stream.write_all(&PROTOCOL_VERSION.to_le_bytes())?;
+    stream.flush()?;
+    if theirs != PROTOCOL_VERSION {
+        return Err(ProtocolError::VersionMismatch {
+            ours: PROTOCOL_VERSION,
+            theirs,
+        });
+    }
+    Ok(())
+}
+
+/// Writes a single length prefixed frame
+pub fn write_message<W: Write, T: Serialize>(
+    stream: &mut W,
+    message: &T,
+) -> Result<(), ProtocolError> {
+    let payload = codec().serialize(message)?;
+    let len = match u32::try_from(payload.len()) {
+        Ok(len) if len <= MAX_FRAME_SIZE => len,
+        _ => return Err(ProtocolError::FrameTooLarge(payload.len())),
+    };
+
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(&payload)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Reads a single length prefixed frame
+pub fn read_message<R: Read, T: DeserializeOwned>(stream: &mut R) -> Result<T, ProtocolError> {
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf)?;
+    let len = u32::from_le_bytes(buf);
+    if len > MAX_FRAME_SIZE {
+        return Err(ProtocolError::FrameTooLarge(len as usize));
+    }
+
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(codec().deserialize(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Reads from `input` and keeps what gets written
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Pipe {
+        fn new(input: Vec<u8>) -> Self {
+            Pipe {
+                input: Cursor::new(input),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn hello(version: u32) -> Vec<u8> {
+        let mut hello = MAGIC.to_vec();
+        hello.extend(version.to_le_bytes());
+        hello
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let mut frame = Vec::new();
+        write_message(&mut frame, &Response::Wallpaper(PathBuf::from("/a.png"))).unwrap();
+        let response: Response = read_message(&mut Cursor::new(frame)).unwrap();
+        assert!(matches!(response, Response::Wallpaper(path) if path == Path::new("/a.png")));
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut frame = (MAX_FRAME_SIZE + 1).to_le_bytes().to_vec();
+        frame.extend([0; 16]);
+        let result: Result<Response, _> = read_message(&mut Cursor::new(frame));
+        assert!(
+            matches!(result, Err(ProtocolError::FrameTooLarge(len)) if len == MAX_FRAME_SIZE as usize + 1)
+        );
+
+        let huge = Response::Paths(vec![PathBuf::from("x".repeat(1024)); 1024]);
+        let mut frame = Vec::new();
+        assert!(write_message(&mut frame, &huge).is_err());
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let mut frame = 8u32.to_le_bytes().to_vec();
+        frame.extend([0; 4]);
+        let result: Result<Response, _> = read_message(&mut Cursor::new(frame));
+        assert!(matches!(result, Err(ProtocolError::Io(_))));
+    }
+
+    #[test]
+    fn handshake() {
+        let mut server = Pipe::new(hello(PROTOCOL_VERSION));
+        server_handshake(&mut server).unwrap();
+        let mut client = Pipe::new(server.output);
+        client_handshake(&mut client).unwrap();
+        assert_eq!(client.output, hello(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut hello = hello(PROTOCOL_VERSION);
+        hello[..4].copy_from_slice(b"HTTP");
+        let mut server = Pipe::new(hello);
+        assert!(matches!(
+            server_handshake(&mut server),
+            Err(ProtocolError::BadMagic)
+        ));
+        // Nothing is answered to strangers
+        assert!(server.output.is_empty());
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let theirs = PROTOCOL_VERSION + 1;
+        let mut server = Pipe::new(hello(theirs));
+        assert!(matches!(
+            server_handshake(&mut server),
+            Err(ProtocolError::VersionMismatch { ours, theirs: t })
+                if ours == PROTOCOL_VERSION && t == theirs
+        ));
+        // The client still learns the daemon's version
+        assert_eq!(server.output, PROTOCOL_VERSION.to_le_bytes());
+
+        let mut client = Pipe::new(theirs.to_le_bytes().to_vec());
+        assert!(matches!(
+            client_handshake(&mut client),
+            Err(ProtocolError::VersionMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn json_with_non_utf8_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = PathBuf::from(OsStr::from_bytes(b"/pictures/caf\xe9.png"));
+        assert_eq!(
+            Response::Wallpaper(path.clone()).to_json(),
+            json!({ "wallpaper": "/pictures/caf\u{fffd}.png" })
+        );
+        assert_eq!(
+            Response::Paths(vec![path.clone()]).to_json(),
+            json!({ "paths": ["/pictures/caf\u{fffd}.png"] })
+        );
+        let source = SourceSpec {
+            path,
+            ..Default::default()
+        };
+        assert_eq!(
+            Response::Sources(vec![source]).to_json()["sources"][0]["path"],
+            "/pictures/caf\u{fffd}.png"
+        );
+    }
+}
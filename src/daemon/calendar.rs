@@ -0,0 +1,217 @@
+use chrono::{Datelike, Duration as ChronoDuration, Month, NaiveDate, Weekday};
+use serde::Deserialize;
+
+/// Holidays calendar rules can refer to by name
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Holiday {
+    NewYear,
+    ValentinesDay,
+    GoodFriday,
+    Easter,
+    EasterMonday,
+    Ascension,
+    Pentecost,
+    Halloween,
+    /// Fourth Thursday of November
+    Thanksgiving,
+    ChristmasEve,
+    Christmas,
+    NewYearsEve,
+}
+
+impl Holiday {
+    /// Date of the holiday in `year`
+    pub fn date(self, year: i32) -> Option<NaiveDate> {
+        let fixed = |month, day| NaiveDate::from_ymd_opt(year, month, day);
+        let after_easter = |days| easter(year)?.checked_add_signed(ChronoDuration::days(days));
+        match self {
+            Holiday::NewYear => fixed(1, 1),
+            Holiday::ValentinesDay => fixed(2, 14),
+            Holiday::GoodFriday => after_easter(-2),
+            Holiday::Easter => easter(year),
+            Holiday::EasterMonday => after_easter(1),
+            Holiday::Ascension => after_easter(39),
+            Holiday::Pentecost => after_easter(49),
+            Holiday::Halloween => fixed(10, 31),
+            Holiday::Thanksgiving => {
+                NaiveDate::from_weekday_of_month_opt(year, 11, Weekday::Thu, 4)
+            }
+            Holiday::ChristmasEve => fixed(12, 24),
+            Holiday::Christmas => fixed(12, 25),
+            Holiday::NewYearsEve => fixed(12, 31),
+        }
+    }
+}
+
+/// Easter Sunday in the Gregorian calendar, with the anonymous algorithm
+fn easter(year: i32) -> Option<NaiveDate> {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32)
+}
+
+/// The days a calendar rule is active on
+#[derive(Debug, Clone)]
+pub enum Days {
+    /// Every day of these months, January is 1
+    Months(Vec<u32>),
+    /// From one day of the year to another, wrapping around New Year
+    Yearly { from: (u32, u32), to: (u32, u32) },
+    /// A single stretch of days
+    Once { from: NaiveDate, to: NaiveDate },
+    /// `length` days starting `offset` days after a holiday
+    Holiday {
+        holiday: Holiday,
+        offset: i64,
+        length: i64,
+    },
+}
+
+impl Days {
+    /// Parses month names like `mar` and ranges like `dec-feb`
+    pub fn months(months: &[String]) -> Result<Self, String> {
+        let month = |name: &str| {
+            name.trim()
+                .parse::<Month>()
+                .map(|month| month.number_from_month())
+                .map_err(|_| format!("invalid month {name:?}"))
+        };
+        let mut numbers = Vec::new();
+        for entry in months {
+            match entry.split_once('-') {
+                Some((from, to)) => {
+                    let (from, to) = (month(from)?, month(to)?);
+                    let mut month = from;
+                    numbers.push(month);
+                    while month != to {
+                        month = month % 12 + 1;
+                        numbers.push(month);
+                    }
+                }
+                None => numbers.push(month(entry)?),
+            }
+        }
+        Ok(Days::Months(numbers))
+    }
+
+    /// Parses `MM-DD` for every year or `YYYY-MM-DD` for a single one
+    pub fn dates(from: &str, to: &str) -> Result<Self, String> {
+        let date = |date: &str| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|e| format!("invalid date {date:?}: {e}"))
+        };
+        // Leap years allow every day
+        let day = |day: &str| {
+            date(&format!("2000-{day}"))
+                .map(|date| (date.month(), date.day()))
+                .map_err(|_| format!("invalid day {day:?}, expected MM-DD"))
+        };
+        match (from.len() > 5, to.len() > 5) {
+            (false, false) => Ok(Days::Yearly {
+                from: day(from)?,
+                to: day(to)?,
+            }),
+            (true, true) => {
+                let (from, to) = (date(from)?, date(to)?);
+                if to < from {
+                    return Err(format!("{to} is before {from}"));
+                }
+                Ok(Days::Once { from, to })
+            }
+            _ => Err("from and to both need a year or neither".to_string()),
+        }
+    }
+
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        match self {
+            Days::Months(months) => months.contains(&date.month()),
+            Days::Yearly { from, to } => {
+                let day = (date.month(), date.day());
+                if from <= to {
+                    *from <= day && day <= *to
+                } else {
+                    *from <= day || day <= *to
+                }
+            }
+            Days::Once { from, to } => *from <= date && date <= *to,
+            Days::Holiday {
+                holiday,
+                offset,
+                length,
+            } => {
+                // With an offset the days can fall into the year before or after
+                (date.year() - 1..=date.year() + 1).any(|year| {
+                    holiday
+                        .date(year)
+                        .and_then(|day| day.checked_add_signed(ChronoDuration::days(*offset)))
+                        .is_some_and(|start| start <= date && (date - start).num_days() < *length)
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn easter_dates() {
+        assert_eq!(Holiday::Easter.date(2024), Some(date(2024, 3, 31)));
+        assert_eq!(Holiday::Easter.date(2025), Some(date(2025, 4, 20)));
+        assert_eq!(Holiday::GoodFriday.date(2025), Some(date(2025, 4, 18)));
+    }
+
+    #[test]
+    fn month_range_wraps() {
+        let days = Days::months(&["dec-feb".to_string()]).unwrap();
+        assert!(matches!(&days, Days::Months(months) if *months == [12, 1, 2]));
+        assert!(days.contains(date(2024, 12, 1)));
+        assert!(days.contains(date(2025, 1, 15)));
+        assert!(days.contains(date(2025, 2, 28)));
+        assert!(!days.contains(date(2025, 3, 1)));
+        assert!(!days.contains(date(2024, 11, 30)));
+    }
+
+    #[test]
+    fn invalid_month() {
+        assert!(Days::months(&["dec-foo".to_string()]).is_err());
+    }
+
+    #[test]
+    fn days_across_new_year() {
+        let days = Days::dates("12-20", "01-05").unwrap();
+        assert!(days.contains(date(2024, 12, 20)));
+        assert!(days.contains(date(2024, 12, 31)));
+        assert!(days.contains(date(2025, 1, 1)));
+        assert!(days.contains(date(2025, 1, 5)));
+        assert!(!days.contains(date(2025, 1, 6)));
+        assert!(!days.contains(date(2024, 12, 19)));
+        assert!(!days.contains(date(2024, 7, 1)));
+    }
+
+    #[test]
+    fn days_within_a_year() {
+        let days = Days::dates("03-01", "03-31").unwrap();
+        assert!(days.contains(date(2025, 3, 15)));
+        assert!(!days.contains(date(2025, 4, 1)));
+        assert!(!days.contains(date(2025, 2, 28)));
+    }
+}
@@ -0,0 +1,69 @@
+use serde::Deserialize;
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use common::{NextImage, WallpaperError};
+
+use crate::schedule::{CalendarConfig, RuleConfig};
+use crate::sun::Sun;
+use crate::WallpaperMethod;
+
+/// Contents of the config file
+///
+/// The settings at the top are the same as the daemon's options, which win
+/// over them.
+///
+/// ```toml
+/// default = "/usr/share/backgrounds/default.png"
+/// directories = ["/home/me/pictures:recursive", "/home/me/wallpapers"]
+/// mode = "shuffle"
+/// interval = 300
+///
+/// [backend]
+/// name = "hyprpaper"
+/// monitors = ["DP-1", "HDMI-A-1"]
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Image to show by default
+    pub default: Option<PathBuf>,
+    /// Socket for communication
+    pub socket: Option<PathBuf>,
+    /// Directories to search for images, with options like `-w`
+    #[serde(default)]
+    pub directories: Vec<String>,
+    pub mode: Option<NextImage>,
+    /// Seconds between wallpaper changes
+    pub interval: Option<u64>,
+    /// Maximum size of the history
+    pub history_length: Option<usize>,
+    /// Program that gets called to change the wallpaper
+    pub backend: Option<WallpaperMethod>,
+    /// Rules changing the settings at certain times
+    #[serde(default)]
+    pub schedule: Vec<RuleConfig>,
+    /// Rules changing the settings on certain days
+    #[serde(default)]
+    pub calendar: Vec<CalendarConfig>,
+    /// Where the daemon is, to switch between day and night
+    pub sun: Option<Sun>,
+}
+
+impl Config {
+    /// Reads the config, a missing file is the same as an empty one
+    pub fn load(path: &Path) -> Result<Self, WallpaperError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(WallpaperError::io(path, e)),
+        };
+        toml::from_str(&content).map_err(|e| WallpaperError::Config {
+            path: path.to_path_buf(),
+            message: e.message().to_string(),
+        })
+    }
+}
@@ -1,36 +1,78 @@
 use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::io::Write;
 use std::os::unix::net::*;
 use std::os::unix::prelude::{FromRawFd, RawFd};
 use std::path::PathBuf;
 use std::process::exit;
-use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
-use std::thread::{self, sleep};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use clap::{Args, Parser, Subcommand};
-use log::{debug, error, info};
+use common::files;
+use common::protocol::{self, ProtocolError, Request, Response};
+use common::{
+    parse_duration, Event, NextImage, SortKey, SortOrder, SourceSpec, Status, WallpaperError,
+};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
+mod calendar;
+mod config;
+mod database;
+mod filter;
+mod patterns;
+mod persist;
+mod playlist;
+mod reload;
+mod scan;
+mod schedule;
+mod sort;
 mod state;
+mod sun;
+mod watch;
 
+use config::Config;
+use database::Database;
+use filter::{ImageFilter, ImageFormat};
+use patterns::Patterns;
+use persist::SavedState;
+use reload::{Reloader, Resolved};
+use scan::{ScanOptions, SourceDefaults};
+use schedule::Schedule;
 use state::*;
+use watch::watch;
 
-//TODO: error handling
+/// How long a client may take to send its request or accept a response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// Struct to hold and parse cli arguments
-#[derive(Parser, Debug)]
+///
+/// Options that can be set in the config file as well override it.
+#[derive(Parser, Debug, Clone)]
 #[clap(version)]
 pub struct Cli {
     /// Image to show by default
     #[clap(short, long, value_parser, value_name = "FILE")]
-    default: PathBuf,
-    /// Socket for communication
+    default: Option<PathBuf>,
+    /// Socket for communication [default: $XDG_RUNTIME_DIR/wallpaperd]
     #[clap(short, long, value_parser, value_name = "FILE")]
     socket: Option<PathBuf>,
-    /// Directory to search for images
-    #[clap(short, long, value_parser, value_name = "DIRECTORY")]
-    wallpaper_directory: PathBuf,
+    /// Directory to search for images, can be given several times
+    ///
+    /// The options below apply to every directory, a directory can add its
+    /// own after a colon: recursive, no-recursive, max-depth=DEPTH, hidden,
+    /// no-hidden, extensions=EXT+EXT, sniff and no-sniff. E.g.
+    /// `~/pictures:recursive,hidden`
+    #[clap(short, long, parse(try_from_str), value_name = "DIRECTORY[:OPTIONS]")]
+    wallpaper_directory: Vec<SourceSpec>,
+    /// Show the images listed in this file instead, one path per line
+    #[clap(long, value_parser, value_name = "FILE")]
+    playlist: Option<PathBuf>,
     /// Seach for images recursively
     #[clap(
         short,
@@ -40,24 +82,72 @@ pub struct Cli {
         default_value_t = false
     )]
     recursivly: bool,
-    /// Time in seconds between wallpaper changes
+    /// How many levels of subdirectories to search, unlimited by default
+    #[clap(long, value_name = "DEPTH", requires = "recursivly")]
+    max_depth: Option<usize>,
+    /// Also use files and directories starting with a dot
+    #[clap(long)]
+    hidden: bool,
+    /// File extensions considered to be images
+    #[clap(
+        long,
+        value_name = "EXTENSIONS",
+        value_delimiter = ',',
+        default_value = "png,jpg,jpeg,webp,gif,bmp"
+    )]
+    extensions: Vec<String>,
+    /// Trust file extensions instead of checking the content of each file
+    #[clap(long)]
+    no_sniff: bool,
+    /// Only show images matching one of these patterns, can be given several
+    /// times
+    ///
+    /// Patterns are globs matched against the path relative to the wallpaper
+    /// directory. Without a slash they match any file or directory name on
+    /// the way, a trailing slash only matches directories.
+    #[clap(long, value_name = "PATTERN")]
+    include: Vec<String>,
+    /// Never show images matching this pattern, can be given several times
+    #[clap(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Time in seconds between wallpaper changes [default: 60]
     #[clap(short, long, parse(try_from_str = parse_duration))]
     interval: Option<Duration>,
+    /// Config file, re-read on `wp reload` and SIGHUP [default: $XDG_CONFIG_HOME/wallpaper/config.toml]
+    #[clap(long, value_parser, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// File to store ratings in [default: $XDG_DATA_HOME/wallpaper/database.json]
+    #[clap(long, value_parser, value_name = "FILE")]
+    database: Option<PathBuf>,
+    /// File the state is kept in across restarts [default: $XDG_STATE_HOME/wallpaperd/state]
+    #[clap(long, value_parser, value_name = "FILE")]
+    state: Option<PathBuf>,
+    /// Start with the settings given here and don't save the state
+    #[clap(long, conflicts_with = "state")]
+    no_persist: bool,
     /// File descriptor to write to to signal readiness
     #[clap(long)]
     fd: Option<RawFd>,
-    /// Maximum size of the history (used for getting the previous wallpaper)
-    #[clap(long, default_value_t = 25)]
-    history_length: usize,
-    #[clap(short, long, arg_enum, default_value_t = NextImage::Static)]
-    mode: NextImage,
+    /// Maximum size of the history (used for getting the previous wallpaper) [default: 25]
+    #[clap(long)]
+    history_length: Option<usize>,
+    /// [default: static]
+    #[clap(short, long, arg_enum)]
+    mode: Option<NextImage>,
+    /// Order in which linear mode shows the images
+    #[clap(long, arg_enum, value_name = "KEY", default_value_t = SortKey::Name)]
+    sort: SortKey,
+    /// Walk through the images in descending order
+    #[clap(long)]
+    reverse: bool,
     /// Which underlying program to call to change the wallpaper
     #[clap(subcommand)]
-    pub method: WallpaperMethod,
+    pub method: Option<WallpaperMethod>,
 }
 
 /// Program that gets called to change the active wallpaper
-#[derive(Subcommand, Debug)]
+#[derive(Subcommand, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "name", rename_all = "lowercase")]
 pub enum WallpaperMethod {
     /// Use Feh (for xorg)
     Feh,
@@ -65,76 +155,180 @@ pub enum WallpaperMethod {
     Hyprpaper(HyprpaperOptions),
 }
 
+impl WallpaperMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WallpaperMethod::Feh => "feh",
+            WallpaperMethod::Hyprpaper(_) => "hyprpaper",
+        }
+    }
+
+    /// Image formats the program is able to display
+    pub fn supported_formats(&self) -> Vec<ImageFormat> {
+        match self {
+            WallpaperMethod::Feh => ImageFormat::ALL.to_vec(),
+            WallpaperMethod::Hyprpaper(_) => {
+                vec![ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Webp]
+            }
+        }
+    }
+}
+
 /// Hyprpaper needs a list of monitors. This struct holds them
-#[derive(Args, Debug)]
+#[derive(Args, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct HyprpaperOptions {
     #[clap(value_parser)]
     monitors: Vec<String>,
 }
 
-fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntError> {
-    let seconds = arg.parse()?;
-    Ok(std::time::Duration::from_secs(seconds))
-}
-
 fn main() {
     pretty_env_logger::init();
 
     let cli = Cli::parse();
     debug!("Command run was:\n{:?}", &cli);
-    let socket = cli.socket.unwrap_or_else(|| {
-        if let Ok(path) = std::env::var("XDG_RUNTIME_DIR") {
-            let mut pathbuf = PathBuf::new();
-            pathbuf.push(path);
-            pathbuf.push("wallpaperd");
-            pathbuf
-        } else {
-            PathBuf::from_str("/tmp/wallpaperd").unwrap()
+    let config_path = cli.config.clone().unwrap_or_else(files::config_path);
+    let (resolved, schedule) = match Config::load(&config_path).and_then(|config| {
+        let resolved = Resolved::new(&cli, &config, &config_path)?;
+        let schedule = Schedule::new(config.schedule, config.calendar, config.sun)?;
+        Ok((resolved, schedule))
+    }) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("{e}");
+            exit(1);
         }
-    });
+    };
+    let socket = resolved.socket.clone();
+
+    let source_defaults = SourceDefaults {
+        options: ScanOptions {
+            recursive: cli.recursivly,
+            max_depth: cli.max_depth,
+            hidden: cli.hidden,
+        },
+        filter: ImageFilter {
+            extensions: cli
+                .extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                .collect(),
+            sniff: !cli.no_sniff,
+            supported: resolved.method.supported_formats(),
+        },
+    };
+    let sources = resolved
+        .directories
+        .iter()
+        .map(|spec| source_defaults.source(spec.clone()))
+        .collect();
+    let patterns = match Patterns::new(&cli.include, &cli.exclude) {
+        Ok(patterns) => patterns,
+        Err(e) => {
+            error!("{e}");
+            exit(1);
+        }
+    };
+    let settings = Settings {
+        change_interval: resolved.interval,
+        default_image: resolved.default.clone(),
+        action: resolved.mode,
+        explicit_action: resolved.explicit_mode,
+        explicit_interval: resolved.explicit_interval,
+        history_max_size: resolved.history_length,
+        sort_order: SortOrder {
+            key: cli.sort,
+            reverse: cli.reverse,
+        },
+        source_defaults,
+        patterns,
+        playlist: cli.playlist.clone(),
+        schedule,
+        state_file: if cli.no_persist {
+            None
+        } else {
+            Some(cli.state.clone().unwrap_or_else(SavedState::default_path))
+        },
+    };
+    let database = Database::load(cli.database.clone().unwrap_or_else(Database::default_path));
+    let method = resolved.method.clone();
+    let data = Arc::new(Mutex::new(State::new(settings, sources, method, database)));
+    let fd = cli.fd;
+    let reloader = Arc::new(Reloader::new(cli, config_path, resolved));
 
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP]).expect("Error setting signal hooks");
     let s = socket.clone();
-    ctrlc::set_handler(move || {
-        if fs::remove_file(&s).is_err() {
-            error!("Couldn't delete socket file");
+    let d = data.clone();
+    let r = reloader.clone();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                if let Err(e) = r.reload(&d) {
+                    error!("Couldn't reload the config: {e}");
+                }
+                continue;
+            }
+            d.lock().unwrap().persist();
+            if fs::remove_file(&s).is_err() {
+                error!("Couldn't delete socket file");
+            }
+            exit(1);
         }
-        exit(1);
-    })
-    .expect("Error setting signal hooks");
-
-    let time = cli.interval.unwrap_or(Duration::new(60, 0));
-    let data = Arc::new(Mutex::new(State::new(
-        time,
-        cli.wallpaper_directory,
-        cli.default,
-        cli.mode,
-        cli.method,
-        cli.history_length,
-        cli.recursivly,
-    )));
+    });
 
     info!("Binding socket {:?}", socket);
-    let listener = UnixListener::bind(&socket).unwrap();
+    let listener = match UnixListener::bind(&socket) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Couldn't bind socket {}: {e}", socket.to_string_lossy());
+            exit(1);
+        }
+    };
     let incoming = listener.incoming();
 
-    if cli.fd.is_some() {
-        let mut file = unsafe { File::from_raw_fd(cli.fd.unwrap()) };
-        writeln!(&mut file).unwrap();
+    if let Some(fd) = fd {
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        if let Err(e) = writeln!(&mut file) {
+            error!("Couldn't signal readiness: {e}");
+        }
     }
 
     let d = data.clone();
     thread::spawn(move || change_interval(d));
+    let sources = data.lock().unwrap().get_watched_sources();
+    for source in sources {
+        let d = data.clone();
+        thread::spawn(move || watch(d, source));
+    }
 
+    let stop = Arc::new(AtomicBool::new(false));
     for stream in incoming {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Couldn't accept connection: {e}");
+                continue;
+            }
+        };
+
         let d = data.clone();
-        let handle = thread::spawn(move || handle_connection(stream.unwrap(), d));
-        if let Ok(res) = handle.join() {
-            if res {
-                break;
+        let r = reloader.clone();
+        let stop = stop.clone();
+        let socket = socket.clone();
+        thread::spawn(move || {
+            if handle_connection(stream, d, r) {
+                stop.store(true, Ordering::SeqCst);
+                // Wake up the accept loop so it notices the stop flag
+                if UnixStream::connect(&socket).is_err() {
+                    error!("Couldn't wake up the listener");
+                }
             }
-        }
+        });
     }
 
+    data.lock().unwrap().persist();
     if fs::remove_file(&socket).is_err() {
         error!("Couldn't delete socket file");
         exit(1);
@@ -142,55 +336,64 @@ fn main() {
     exit(0);
 }
 
-fn read_from_stream(mut stream: &UnixStream) -> String {
-    let string = String::new();
-
-    //First run get length
-    let mut buf: [u8; 8] = [0; 8];
-
-    if stream.read_exact(&mut buf).is_err() {
-        return string;
-    }
-
-    let mut buffer = vec![0; usize::from_ne_bytes(buf)];
-    if stream.read_exact(&mut buffer).is_err() {
-        string
-    } else {
-        String::from_utf8(buffer).unwrap()
-    }
-}
-
-#[derive(Parser)]
-struct ClientMessage {
-    #[clap(subcommand)]
-    command: common::Command,
-}
-
 // Thread: Client <---> Server
-fn handle_connection(mut stream: UnixStream, state: Arc<Mutex<State>>) -> bool {
+fn handle_connection(
+    mut stream: UnixStream,
+    state: Arc<Mutex<State>>,
+    reloader: Arc<Reloader>,
+) -> bool {
     use common::*;
-    use std::io::prelude::*;
     info!("Handle new connection");
-    let line = read_from_stream(&stream);
-    let mut response = "".to_string();
+    if let Err(e) = stream
+        .set_read_timeout(Some(CLIENT_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+    {
+        error!("Couldn't set socket timeouts: {e}");
+        return false;
+    }
+    if let Err(e) = protocol::server_handshake(&mut stream) {
+        error!("Handshake failed: {e}");
+        return false;
+    }
+    let request: Request = match protocol::read_message(&mut stream) {
+        Ok(request) => request,
+        Err(e) => {
+            error!("Couldn't read request: {e}");
+            // Let the client know unless the connection itself is broken
+            if let ProtocolError::Encoding(_) | ProtocolError::FrameTooLarge(_) = e {
+                let response = Response::Error(WallpaperError::InvalidRequest(e.to_string()));
+                if let Err(e) = protocol::write_message(&mut stream, &response) {
+                    error!("Couldn't send response: {e}");
+                }
+            }
+            return false;
+        }
+    };
 
-    debug!("Got {}", &line);
-    let mut split: Vec<&str> = line.split(' ').collect();
-    split.insert(0, " ");
+    debug!("Got {:?}", &request);
     let mut stop_server = false;
-    match ClientMessage::parse_from(split).command {
-        Command::Next => state
+    let result = match request {
+        Request::Next => state
             .lock()
             .unwrap()
-            .change_image(ChangeImageDirection::Next),
-        Command::Stop => stop_server = true,
-        Command::Previous => state
+            .change_image(ChangeImageDirection::Next)
+            .map(|_| Response::Ok),
+        Request::Stop => {
+            stop_server = true;
+            Ok(Response::Ok)
+        }
+        Request::Previous => state
             .lock()
             .unwrap()
-            .change_image(ChangeImageDirection::Previous),
-        Command::Mode(mode) => match mode {
+            .change_image(ChangeImageDirection::Previous)
+            .map(|_| Response::Ok),
+        Request::Mode(mode) => match mode {
             ModeArgs::Linear => state.lock().unwrap().update_action(NextImage::Linear, None),
             ModeArgs::Random => state.lock().unwrap().update_action(NextImage::Random, None),
+            ModeArgs::Shuffle => state
+                .lock()
+                .unwrap()
+                .update_action(NextImage::Shuffle, None),
             ModeArgs::Static(img) => match img.path {
                 Some(path) => state
                     .lock()
@@ -198,57 +401,205 @@ fn handle_connection(mut stream: UnixStream, state: Arc<Mutex<State>>) -> bool {
                     .update_action(NextImage::Static, Some(path)),
                 None => state.lock().unwrap().update_action(NextImage::Static, None),
             },
-        },
-        Command::Fallback => state.lock().unwrap().save(),
-        Command::Interval(d) => {
-            state.lock().unwrap().change_interval(d.duration);
         }
-        Command::Get(what) => {
-            response = match what {
-                GetArgs::Wallpaper => state
-                    .lock()
-                    .unwrap()
-                    .get_current_image()
-                    .clone()
-                    .to_str()
-                    .unwrap_or("ERROR")
-                    .to_owned(),
-                GetArgs::Duration => state
-                    .lock()
-                    .unwrap()
-                    .get_change_interval()
-                    .as_secs()
-                    .to_string(),
-                GetArgs::Mode => {
-                    let action = state.lock().unwrap().get_action();
-                    match action {
-                        NextImage::Linear => "Linear".to_string(),
-                        NextImage::Static => "Static".to_string(),
-                        NextImage::Random => "Random".to_string(),
-                    }
-                }
-                GetArgs::Fallback => state.lock().unwrap().get_fallback().to_string(),
+        .map(|_| Response::Ok),
+        Request::Fallback => state.lock().unwrap().save().map(|_| Response::Ok),
+        Request::Reload => reloader.reload(&state).map(|_| Response::Ok),
+        Request::Interval(d) => state
+            .lock()
+            .unwrap()
+            .change_interval(d.duration)
+            .map(|_| Response::Ok),
+        Request::Pause => state.lock().unwrap().pause().map(|_| Response::Ok),
+        Request::Resume => state.lock().unwrap().resume().map(|_| Response::Ok),
+        Request::TogglePause => state.lock().unwrap().toggle_pause().map(|_| Response::Ok),
+        Request::Get(GetArgs::Sun) => state.lock().unwrap().get_sun().map(Response::Sun),
+        Request::Get(what) => Ok(match what {
+            GetArgs::Wallpaper => {
+                Response::Wallpaper(state.lock().unwrap().get_current_image().clone())
             }
+            GetArgs::Duration => Response::Duration(state.lock().unwrap().get_change_interval()),
+            GetArgs::Remaining => {
+                Response::Remaining(state.lock().unwrap().get_time_until_change())
+            }
+            GetArgs::Mode => {
+                let state = state.lock().unwrap();
+                Response::Mode(state.get_action(), state.get_active_rules())
+            }
+            GetArgs::Fallback => Response::Fallback(state.lock().unwrap().get_fallback()),
+            GetArgs::Paused => Response::Paused(state.lock().unwrap().is_paused()),
+            GetArgs::Rating => Response::Rating(state.lock().unwrap().get_rating(None)),
+            GetArgs::Tags => Response::Tags(state.lock().unwrap().get_tags()),
+            GetArgs::Sun => unreachable!("handled above"),
+            GetArgs::Sort => Response::Sort(state.lock().unwrap().get_sort_order()),
+            GetArgs::Status => Response::Status(state.lock().unwrap().get_status()),
+            GetArgs::State => Response::State(state.lock().unwrap().snapshot()),
+        }),
+        Request::Sort(order) => {
+            state.lock().unwrap().set_sort_order(order);
+            Ok(Response::Ok)
         }
-    }
+        Request::Rate(args) => state
+            .lock()
+            .unwrap()
+            .rate(args.path, args.stars)
+            .map(|_| Response::Ok),
+        Request::Fav(img) => state
+            .lock()
+            .unwrap()
+            .toggle_favourite(img.path)
+            .map(Response::Rating),
+        Request::Tag(args) => state
+            .lock()
+            .unwrap()
+            .tag(args.path, args.tag)
+            .map(|_| Response::Ok),
+        Request::Untag(args) => state
+            .lock()
+            .unwrap()
+            .untag(args.path, args.tag)
+            .map(|_| Response::Ok),
+        Request::Collection(what) => match what {
+            CollectionArgs::Use(collection) => state
+                .lock()
+                .unwrap()
+                .use_collection(Some(collection.name))
+                .map(|_| Response::Ok),
+            CollectionArgs::Clear => state
+                .lock()
+                .unwrap()
+                .use_collection(None)
+                .map(|_| Response::Ok),
+            CollectionArgs::List => state
+                .lock()
+                .unwrap()
+                .get_collections()
+                .map(Response::Collections),
+        },
+        Request::Ban => state.lock().unwrap().ban().map(|_| Response::Ok),
+        Request::Unban(img) => state.lock().unwrap().unban(img.path).map(|_| Response::Ok),
+        Request::List(what) => Ok(match what {
+            ListArgs::Banned => Response::Paths(state.lock().unwrap().get_banned()),
+        }),
+        Request::Source(what) => match what {
+            SourceArgs::Add(spec) => state.lock().unwrap().add_source(spec).map(|source| {
+                let d = state.clone();
+                thread::spawn(move || watch(d, source));
+                Response::Ok
+            }),
+            SourceArgs::Remove(dir) => state
+                .lock()
+                .unwrap()
+                .remove_source(&dir.path)
+                .map(|_| Response::Ok),
+            SourceArgs::List => Ok(Response::Sources(state.lock().unwrap().get_sources())),
+        },
+        Request::Filter(what) => match what {
+            FilterArgs::Include(arg) => state
+                .lock()
+                .unwrap()
+                .include_pattern(&arg.pattern)
+                .map(|_| Response::Ok),
+            FilterArgs::Exclude(arg) => state
+                .lock()
+                .unwrap()
+                .exclude_pattern(&arg.pattern)
+                .map(|_| Response::Ok),
+            FilterArgs::Remove(arg) => state
+                .lock()
+                .unwrap()
+                .remove_pattern(&arg.pattern)
+                .map(|_| Response::Ok),
+            FilterArgs::List => Ok(Response::Filters(state.lock().unwrap().get_filters())),
+        },
+        Request::Playlist(what) => match what {
+            PlaylistArgs::Load(file) => state
+                .lock()
+                .unwrap()
+                .load_playlist(file.path)
+                .map(Response::ImageCount),
+            PlaylistArgs::Save(args) => state
+                .lock()
+                .unwrap()
+                .save_playlist(&args.path, args.history)
+                .map(|_| Response::Ok),
+            PlaylistArgs::Unload => state
+                .lock()
+                .unwrap()
+                .unload_playlist()
+                .map(Response::ImageCount),
+        },
+        Request::Rescan => state.lock().unwrap().rescan().map(Response::ImageCount),
+        Request::Schedule(what) => Ok(match what {
+            ScheduleArgs::List => Response::Transitions(state.lock().unwrap().get_transitions()),
+        }),
+        Request::Subscribe => {
+            let events = state.lock().unwrap().subscribe();
+            send_events(stream, events);
+            return false;
+        }
+    };
+    let response = result.unwrap_or_else(|e| {
+        warn!("Request failed: {e}");
+        Response::Error(e)
+    });
 
-    stream.write_all(response.as_bytes()).unwrap();
+    if let Err(e) = protocol::write_message(&mut stream, &response) {
+        error!("Couldn't send response: {e}");
+    }
     stop_server
 }
 
+// Thread: Server ---> Subscribed client
+fn send_events(mut stream: UnixStream, events: Receiver<Event>) {
+    info!("Client subscribed");
+    for event in events {
+        if let Err(e) = protocol::write_message(&mut stream, &Response::Event(event)) {
+            info!("Subscriber went away: {e}");
+            return;
+        }
+    }
+}
+
+// Thread: Timer ---> State
+/// Changes the image every interval and applies the schedule on time
+///
+/// The state wakes the timer up whenever the countdown changes, e.g. after
+/// `next` or a new interval, the lock is only held while not waiting.
 fn change_interval(data: Arc<Mutex<State>>) {
-    let mut time = {
-        //Go out of scope to unlock again
-        let unlocked = data.lock().unwrap();
-        unlocked.get_change_interval()
-    };
+    let mut unlocked = data.lock().unwrap();
+    let timer = unlocked.timer();
+    unlocked.apply_schedule();
+    let interval = unlocked.get_change_interval();
+    unlocked.set_next_change(interval);
     loop {
-        sleep(time);
-        {
-            //Go out of scope to unlock again
-            let mut unlocked = data.lock().unwrap();
-            unlocked.change_image(ChangeImageDirection::Next);
-            time = unlocked.get_change_interval()
+        let until_change = unlocked
+            .get_next_change()
+            .map(|at| at.saturating_duration_since(Instant::now()));
+        // Paused timers only wake up for the schedule or when resumed
+        let timeout = match (until_change, unlocked.get_time_until_transition()) {
+            (Some(change), Some(transition)) => Some(change.min(transition)),
+            (change, transition) => change.or(transition),
+        };
+        unlocked = match timeout {
+            Some(timeout) => timer.wait_timeout(unlocked, timeout).unwrap().0,
+            None => timer.wait(unlocked).unwrap(),
         };
+
+        unlocked.apply_schedule();
+        match unlocked.get_next_change() {
+            Some(next_change) if Instant::now() >= next_change => {}
+            _ => continue,
+        }
+        match unlocked.change_image(ChangeImageDirection::Next) {
+            // Nothing to do
+            Err(WallpaperError::StaticMode | WallpaperError::FallbackActive) => {}
+            // Already reported when the images went missing
+            Err(e) if unlocked.get_status() == Status::NoImages => debug!("{e}"),
+            Err(e) => error!("{e}"),
+            Ok(()) => {}
+        }
+        let interval = unlocked.get_change_interval();
+        unlocked.set_next_change(interval);
     }
 }
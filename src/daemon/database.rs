@@ -0,0 +1,118 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use common::files::{write_atomic, xdg_path};
+use common::{Rating, WallpaperError};
+
+/// Information about images that has to survive restarts
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Database {
+    #[serde(default)]
+    ratings: HashMap<PathBuf, Rating>,
+    /// Images that must never be shown
+    #[serde(default)]
+    banned: BTreeSet<PathBuf>,
+    /// Tags given by hand, on top of the ones derived from directories
+    #[serde(default)]
+    tags: BTreeMap<PathBuf, BTreeSet<String>>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Database {
+    /// `$XDG_DATA_HOME/wallpaper/database.json`
+    pub fn default_path() -> PathBuf {
+        xdg_path("XDG_DATA_HOME", ".local/share", "wallpaper/database.json")
+    }
+
+    /// Reads the database, starting with an empty one if it doesn't exist yet
+    pub fn load(path: PathBuf) -> Self {
+        let mut database = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring broken database {}: {e}", path.to_string_lossy());
+                Database::default()
+            }),
+            Err(e) => {
+                info!("Starting a new database ({e})");
+                Database::default()
+            }
+        };
+        database.path = path;
+        database
+    }
+
+    fn save(&self) -> Result<(), WallpaperError> {
+        let content = serde_json::to_string_pretty(self).map_err(|e| {
+            WallpaperError::io(
+                &self.path,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            )
+        })?;
+        write_atomic(&self.path, &content)
+    }
+
+    pub fn rating(&self, image: &Path) -> Rating {
+        self.ratings.get(image).copied().unwrap_or_default()
+    }
+
+    pub fn set_rating(&mut self, image: PathBuf, rating: Rating) -> Result<(), WallpaperError> {
+        if rating == Rating::default() {
+            self.ratings.remove(&image);
+        } else {
+            self.ratings.insert(image, rating);
+        }
+        self.save()
+    }
+
+    pub fn is_banned(&self, image: &Path) -> bool {
+        self.banned.contains(image)
+    }
+
+    pub fn banned(&self) -> impl Iterator<Item = &PathBuf> {
+        self.banned.iter()
+    }
+
+    pub fn ban(&mut self, image: PathBuf) -> Result<(), WallpaperError> {
+        self.banned.insert(image);
+        self.save()
+    }
+
+    /// Returns whether the image was banned at all
+    pub fn unban(&mut self, image: &Path) -> Result<bool, WallpaperError> {
+        if !self.banned.remove(image) {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn tags(&self, image: &Path) -> impl Iterator<Item = &String> {
+        self.tags.get(image).into_iter().flatten()
+    }
+
+    pub fn tag(&mut self, image: PathBuf, tag: String) -> Result<(), WallpaperError> {
+        self.tags.entry(image).or_default().insert(tag);
+        self.save()
+    }
+
+    /// Returns whether the image had the tag at all
+    pub fn untag(&mut self, image: &Path, tag: &str) -> Result<bool, WallpaperError> {
+        let tags = match self.tags.get_mut(image) {
+            Some(tags) => tags,
+            None => return Ok(false),
+        };
+        if !tags.remove(tag) {
+            return Ok(false);
+        }
+        if tags.is_empty() {
+            self.tags.remove(image);
+        }
+        self.save()?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,144 @@
+use log::trace;
+use std::{fs::File, io::Read, path::Path};
+
+/// Image formats the daemon knows how to recognize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Gif,
+    Bmp,
+}
+
+impl ImageFormat {
+    /// Every known format
+    pub const ALL: [ImageFormat; 5] = [
+        ImageFormat::Png,
+        ImageFormat::Jpeg,
+        ImageFormat::Webp,
+        ImageFormat::Gif,
+        ImageFormat::Bmp,
+    ];
+
+    /// Number of bytes [`ImageFormat::sniff`] needs to look at
+    const HEADER_LEN: usize = 12;
+
+    /// Recognizes a format by the magic bytes at the start of the file
+    pub fn sniff(header: &[u8]) -> Option<ImageFormat> {
+        match header {
+            [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => Some(ImageFormat::Png),
+            [0xff, 0xd8, 0xff, ..] => Some(ImageFormat::Jpeg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => {
+                Some(ImageFormat::Webp)
+            }
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageFormat::Gif),
+            [b'B', b'M', ..] => Some(ImageFormat::Bmp),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from a (lowercase) file extension
+    pub fn from_extension(extension: &str) -> Option<ImageFormat> {
+        match extension {
+            "png" => Some(ImageFormat::Png),
+            "jpg" | "jpeg" | "jpe" | "jfif" => Some(ImageFormat::Jpeg),
+            "webp" => Some(ImageFormat::Webp),
+            "gif" => Some(ImageFormat::Gif),
+            "bmp" | "dib" => Some(ImageFormat::Bmp),
+            _ => None,
+        }
+    }
+}
+
+/// Decides which files are images the backend can display
+#[derive(Debug, Clone)]
+pub struct ImageFilter {
+    /// Lowercase file extensions (without the dot) worth looking at
+    pub extensions: Vec<String>,
+    /// Check the magic bytes instead of trusting the extension
+    pub sniff: bool,
+    /// Formats the backend is able to display
+    pub supported: Vec<ImageFormat>,
+}
+
+impl ImageFilter {
+    pub fn accepts(&self, path: &Path) -> bool {
+        let extension = match path.extension() {
+            Some(extension) => extension.to_string_lossy().to_lowercase(),
+            None => return false,
+        };
+        if !self.extensions.contains(&extension) {
+            trace!("Skipping {}: extension not allowed", path.to_string_lossy());
+            return false;
+        }
+
+        let format = if self.sniff {
+            read_header(path).and_then(|header| ImageFormat::sniff(&header))
+        } else {
+            ImageFormat::from_extension(&extension)
+        };
+        match format {
+            Some(format) if self.supported.contains(&format) => true,
+            Some(format) => {
+                trace!(
+                    "Skipping {}: {format:?} unsupported",
+                    path.to_string_lossy()
+                );
+                false
+            }
+            None => {
+                trace!("Skipping {}: not an image", path.to_string_lossy());
+                false
+            }
+        }
+    }
+}
+
+fn read_header(path: &Path) -> Option<Vec<u8>> {
+    let mut header = Vec::with_capacity(ImageFormat::HEADER_LEN);
+    File::open(path)
+        .ok()?
+        .take(ImageFormat::HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .ok()?;
+    Some(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_magic_numbers() {
+        let headers: [(&[u8], ImageFormat); 6] = [
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", ImageFormat::Png),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF\0", ImageFormat::Jpeg),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", ImageFormat::Webp),
+            (b"GIF87a\x01\0\x01\0", ImageFormat::Gif),
+            (b"GIF89a\x01\0\x01\0", ImageFormat::Gif),
+            (b"BM\x3a\0\0\0\0\0\0\0\x36\0", ImageFormat::Bmp),
+        ];
+        for (header, format) in headers {
+            assert_eq!(ImageFormat::sniff(header), Some(format), "{header:?}");
+        }
+    }
+
+    #[test]
+    fn sniff_truncated_header() {
+        assert_eq!(ImageFormat::sniff(b""), None);
+        assert_eq!(ImageFormat::sniff(b"\x89PNG\r\n"), None);
+        assert_eq!(ImageFormat::sniff(b"\xff\xd8"), None);
+        assert_eq!(ImageFormat::sniff(b"RIFF\x24\0\0\0WEB"), None);
+        assert_eq!(ImageFormat::sniff(b"GIF8"), None);
+        assert_eq!(ImageFormat::sniff(b"B"), None);
+    }
+
+    #[test]
+    fn sniff_text() {
+        assert_eq!(ImageFormat::sniff(b"just some text\n"), None);
+        // RIFF is used for more than images
+        assert_eq!(ImageFormat::sniff(b"RIFF\x24\0\0\0WAVEfmt "), None);
+        assert_eq!(ImageFormat::sniff(b"GIF89b\x01\0"), None);
+    }
+}
@@ -0,0 +1,179 @@
+use glob::{MatchOptions, Pattern};
+use std::path::{Component, Path};
+
+use common::{Filters, WallpaperError};
+
+/// A single include or exclude rule
+///
+/// Rules work like a small subset of gitignore: a pattern without a slash
+/// matches the name of any file or directory on the way to the image, e.g.
+/// `*-raw.*` or `nsfw`. A pattern ending in a slash only matches directories,
+/// e.g. `drafts/`. Any other slash anchors the pattern at the root of the
+/// source, e.g. `work/**/*.png`.
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    directory_only: bool,
+    anchored: bool,
+    /// What the user typed, used to list and remove the rule
+    text: String,
+}
+
+impl Rule {
+    fn new(text: &str) -> Result<Self, WallpaperError> {
+        let directory_only = text.ends_with('/');
+        let trimmed = text.trim_end_matches('/');
+        let anchored = trimmed.contains('/');
+        let pattern = Pattern::new(trimmed.trim_start_matches('/')).map_err(|e| {
+            WallpaperError::InvalidPattern {
+                pattern: text.to_string(),
+                message: e.to_string(),
+            }
+        })?;
+        Ok(Rule {
+            pattern,
+            directory_only,
+            anchored,
+            text: text.to_string(),
+        })
+    }
+
+    /// `relative` is the path of an image relative to its source
+    fn matches(&self, relative: &Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        if self.anchored {
+            // A rule matching a directory matches everything inside of it
+            return relative
+                .ancestors()
+                .skip(usize::from(self.directory_only))
+                .filter(|path| !path.as_os_str().is_empty())
+                .any(|path| self.pattern.matches_path_with(path, options));
+        }
+
+        let mut names: Vec<_> = relative
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        if self.directory_only {
+            // The file name
+            names.pop();
+        }
+        names
+            .iter()
+            .any(|name| self.pattern.matches_with(&name.to_string_lossy(), options))
+    }
+}
+
+/// Include and exclude rules deciding which images of the sources get shown
+///
+/// An image is shown if it matches at least one include rule, or there are
+/// none, and doesn't match any exclude rule.
+#[derive(Debug, Clone, Default)]
+pub struct Patterns {
+    include: Vec<Rule>,
+    exclude: Vec<Rule>,
+}
+
+impl Patterns {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, WallpaperError> {
+        let mut patterns = Patterns::default();
+        for text in include {
+            patterns.include(text)?;
+        }
+        for text in exclude {
+            patterns.exclude(text)?;
+        }
+        Ok(patterns)
+    }
+
+    pub fn allows(&self, relative: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule.matches(relative)))
+            && !self.exclude.iter().any(|rule| rule.matches(relative))
+    }
+
+    pub fn include(&mut self, text: &str) -> Result<(), WallpaperError> {
+        let rule = Rule::new(text)?;
+        self.include.retain(|other| other.text != text);
+        self.include.push(rule);
+        Ok(())
+    }
+
+    pub fn exclude(&mut self, text: &str) -> Result<(), WallpaperError> {
+        let rule = Rule::new(text)?;
+        self.exclude.retain(|other| other.text != text);
+        self.exclude.push(rule);
+        Ok(())
+    }
+
+    /// Drops the rule from both lists
+    pub fn remove(&mut self, text: &str) -> Result<(), WallpaperError> {
+        let count = self.include.len() + self.exclude.len();
+        self.include.retain(|rule| rule.text != text);
+        self.exclude.retain(|rule| rule.text != text);
+        if self.include.len() + self.exclude.len() == count {
+            return Err(WallpaperError::NoSuchPattern(text.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn filters(&self) -> Filters {
+        Filters {
+            include: self.include.iter().map(|rule| rule.text.clone()).collect(),
+            exclude: self.exclude.iter().map(|rule| rule.text.clone()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, relative: &str) -> bool {
+        Rule::new(pattern).unwrap().matches(Path::new(relative))
+    }
+
+    #[test]
+    fn bare_name() {
+        assert!(matches("*-raw.*", "photo-raw.png"));
+        assert!(matches("*-raw.*", "trips/photo-raw.png"));
+        assert!(matches("nsfw", "nsfw/a.png"));
+        assert!(matches("nsfw", "trips/nsfw/a.png"));
+        assert!(!matches("nsfw", "nsfw-not/a.png"));
+        // `*` doesn't cross directories
+        assert!(!matches("trips*png", "trips/a.png"));
+    }
+
+    #[test]
+    fn directory_only() {
+        assert!(matches("drafts/", "drafts/a.png"));
+        assert!(matches("drafts/", "work/drafts/a.png"));
+        assert!(!matches("drafts/", "drafts"));
+        assert!(!matches("drafts/", "work/drafts.png"));
+    }
+
+    #[test]
+    fn anchored() {
+        assert!(matches("work/**/*.png", "work/a.png"));
+        assert!(matches("work/**/*.png", "work/2024/a.png"));
+        assert!(!matches("work/**/*.png", "old/work/a.png"));
+        assert!(matches("/trips", "trips/a.png"));
+        assert!(!matches("/trips", "old/trips/a.png"));
+        assert!(matches("work/drafts/", "work/drafts/a.png"));
+        assert!(!matches("work/drafts/", "work/drafts"));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let patterns = Patterns::new(&["trips/".to_string()], &["*-raw.*".to_string()]).unwrap();
+        assert!(patterns.allows(Path::new("trips/a.png")));
+        assert!(!patterns.allows(Path::new("trips/a-raw.png")));
+        assert!(!patterns.allows(Path::new("work/a.png")));
+        assert!(Patterns::default().allows(Path::new("work/a.png")));
+    }
+}
@@ -0,0 +1,62 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use common::files::{write_atomic, xdg_path};
+use common::{NextImage, WallpaperError};
+
+/// The part of the state that survives restarts
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SavedState {
+    /// Oldest first, the last one is the current wallpaper
+    pub previous: Vec<PathBuf>,
+    /// Images to go forward to again, the next one last
+    pub next: Vec<PathBuf>,
+    pub mode: NextImage,
+    /// Mode to return to once the fallback is turned off
+    pub previous_mode: NextImage,
+    pub interval: Duration,
+    pub fallback: bool,
+    /// Time that was left until the next change, if the timer was paused
+    #[serde(default)]
+    pub paused: Option<Duration>,
+}
+
+impl SavedState {
+    /// `$XDG_STATE_HOME/wallpaperd/state`
+    pub fn default_path() -> PathBuf {
+        xdg_path("XDG_STATE_HOME", ".local/state", "wallpaperd/state")
+    }
+
+    /// Reads the state saved by the last run, if there is a usable one
+    pub fn load(path: &Path) -> Option<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                info!("Starting with a fresh state ({e})");
+                return None;
+            }
+        };
+        match serde_json::from_str(&content) {
+            Ok(saved) => Some(saved),
+            Err(e) => {
+                warn!("Ignoring broken state {}: {e}", path.to_string_lossy());
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), WallpaperError> {
+        let content = serde_json::to_string_pretty(self).map_err(|e| {
+            WallpaperError::io(
+                path,
+                std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+            )
+        })?;
+        write_atomic(path, &content)
+    }
+}
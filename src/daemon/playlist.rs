@@ -0,0 +1,32 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use common::WallpaperError;
+
+/// Reads the entries of a playlist in order
+///
+/// The file has one image per line, relative paths are relative to the
+/// directory of the playlist. Empty lines and lines starting with `#`, like
+/// the `#EXTM3U` header of m3u files, are skipped.
+pub fn read(path: &Path) -> Result<Vec<PathBuf>, WallpaperError> {
+    let content = fs::read_to_string(path).map_err(|e| WallpaperError::io(path, e))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .collect())
+}
+
+/// Writes `images` as an m3u playlist
+pub fn write(path: &Path, images: &[PathBuf]) -> Result<(), WallpaperError> {
+    let mut content = String::from("#EXTM3U\n");
+    for image in images {
+        content.push_str(&image.to_string_lossy());
+        content.push('\n');
+    }
+    fs::write(path, content).map_err(|e| WallpaperError::io(path, e))
+}
@@ -0,0 +1,181 @@
+use log::{info, warn};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use common::files::default_socket;
+use common::{NextImage, SourceSpec, WallpaperError, MAX_INTERVAL};
+
+use crate::config::Config;
+use crate::schedule::Schedule;
+use crate::state::State;
+use crate::watch::watch;
+use crate::{Cli, WallpaperMethod};
+
+/// Settings that can come from the command line or the config file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolved {
+    pub default: PathBuf,
+    pub socket: PathBuf,
+    pub directories: Vec<SourceSpec>,
+    pub mode: NextImage,
+    pub interval: Duration,
+    /// Whether the mode was set on the command line or in the config file
+    pub explicit_mode: bool,
+    /// Whether the interval was set on the command line or in the config file
+    pub explicit_interval: bool,
+    pub history_length: usize,
+    pub method: WallpaperMethod,
+}
+
+impl Resolved {
+    /// Options given on the command line win over the config file at `path`
+    pub fn new(cli: &Cli, config: &Config, path: &Path) -> Result<Self, WallpaperError> {
+        let invalid = |message: String| WallpaperError::Config {
+            path: path.to_path_buf(),
+            message,
+        };
+        let directories = if cli.wallpaper_directory.is_empty() {
+            config
+                .directories
+                .iter()
+                .map(|dir| SourceSpec::from_str(dir).map_err(&invalid))
+                .collect::<Result<_, _>>()?
+        } else {
+            cli.wallpaper_directory.clone()
+        };
+        if directories.is_empty() && cli.playlist.is_none() {
+            return Err(invalid(
+                "no wallpaper directories, give them with -w or directories".to_string(),
+            ));
+        }
+        let interval = match (cli.interval, config.interval) {
+            (Some(interval), _) => interval,
+            (None, Some(0)) => {
+                return Err(invalid(
+                    "the interval must be at least one second".to_string(),
+                ))
+            }
+            (None, Some(secs)) if Duration::from_secs(secs) > MAX_INTERVAL => {
+                return Err(invalid(WallpaperError::IntervalTooLong.to_string()))
+            }
+            (None, Some(secs)) => Duration::from_secs(secs),
+            (None, None) => DEFAULT_INTERVAL,
+        };
+        Ok(Resolved {
+            default: cli
+                .default
+                .clone()
+                .or_else(|| config.default.clone())
+                .ok_or_else(|| {
+                    invalid("no default image, give it with -d or default".to_string())
+                })?,
+            socket: cli
+                .socket
+                .clone()
+                .or_else(|| config.socket.clone())
+                .unwrap_or_else(default_socket),
+            directories,
+            mode: cli.mode.or(config.mode).unwrap_or(NextImage::Static),
+            interval,
+            explicit_mode: cli.mode.is_some() || config.mode.is_some(),
+            explicit_interval: cli.interval.is_some() || config.interval.is_some(),
+            history_length: cli
+                .history_length
+                .or(config.history_length)
+                .unwrap_or(DEFAULT_HISTORY_LENGTH),
+            method: cli
+                .method
+                .clone()
+                .or_else(|| config.backend.clone())
+                .ok_or_else(|| {
+                    invalid("no backend, give it on the command line or as [backend]".to_string())
+                })?,
+        })
+    }
+}
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_HISTORY_LENGTH: usize = 25;
+
+/// Reads the config file again for `wp reload` and SIGHUP
+pub struct Reloader {
+    cli: Cli,
+    path: PathBuf,
+    /// What was in use after the last reload, only changes get applied
+    current: Mutex<Resolved>,
+}
+
+impl Reloader {
+    pub fn new(cli: Cli, path: PathBuf, current: Resolved) -> Self {
+        Reloader {
+            cli,
+            path,
+            current: Mutex::new(current),
+        }
+    }
+
+    /// Applies the parts of the config file that changed since the last time
+    ///
+    /// Nothing is applied if the config file is broken. Directories that
+    /// can't be added are skipped, the socket only changes on a restart.
+    pub fn reload(&self, state: &Arc<Mutex<State>>) -> Result<(), WallpaperError> {
+        info!("Reloading {}", self.path.to_string_lossy());
+        let config = Config::load(&self.path)?;
+        let resolved = Resolved::new(&self.cli, &config, &self.path)?;
+        let schedule = Schedule::new(config.schedule, config.calendar, config.sun)?;
+
+        let mut current = self.current.lock().unwrap();
+        let mut unlocked = state.lock().unwrap();
+        let mut watched = Vec::new();
+        if resolved.socket != current.socket {
+            warn!("The socket only changes when the daemon is restarted");
+        }
+        if resolved.default != current.default {
+            unlocked.set_default_image(resolved.default.clone());
+        }
+        if resolved.history_length != current.history_length {
+            unlocked.set_history_length(resolved.history_length);
+        }
+        if resolved.method != current.method {
+            watched.extend(unlocked.set_backend(resolved.method.clone()));
+        }
+        for spec in &current.directories {
+            if !resolved.directories.contains(spec) {
+                if let Err(e) = unlocked.remove_source(&spec.path) {
+                    warn!("{e}");
+                }
+            }
+        }
+        for spec in &resolved.directories {
+            if !current.directories.contains(spec) {
+                match unlocked.add_source(spec.clone()) {
+                    Ok(source) => watched.push(source),
+                    Err(e) => warn!("{e}"),
+                }
+            }
+        }
+        if resolved.interval != current.interval {
+            if let Err(e) = unlocked.change_interval(resolved.interval) {
+                warn!("{e}");
+            }
+        }
+        if resolved.mode != current.mode {
+            if let Err(e) = unlocked.update_action(resolved.mode, None) {
+                warn!("{e}");
+            }
+        }
+        unlocked.set_schedule(schedule);
+        *current = resolved;
+
+        for source in watched {
+            let d = state.clone();
+            thread::spawn(move || watch(d, source));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+use log::{trace, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use common::{SourceSpec, WallpaperError};
+
+use crate::filter::ImageFilter;
+
+/// Controls which files [`scan`] picks up
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Descend into subdirectories
+    pub recursive: bool,
+    /// How many levels of subdirectories to descend into, `None` for no limit
+    pub max_depth: Option<usize>,
+    /// Include files and directories whose name starts with a dot
+    pub hidden: bool,
+}
+
+/// A directory images get picked from
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub dir: PathBuf,
+    pub options: ScanOptions,
+    pub filter: ImageFilter,
+}
+
+impl Source {
+    /// Every image in the directory the backend can display
+    pub fn images(&self) -> Result<Vec<PathBuf>, WallpaperError> {
+        Ok(scan(&self.dir, &self.options)?
+            .files
+            .into_iter()
+            .filter(|path| self.filter.accepts(path))
+            .collect())
+    }
+
+    /// Whether a file that showed up in one of the scanned directories is an
+    /// image to pick
+    pub fn accepts(&self, path: &Path) -> bool {
+        // Number of subdirectories between the source and the file
+        let depth = match path.strip_prefix(&self.dir) {
+            Ok(relative) => relative.components().count().saturating_sub(1),
+            Err(_) => return false,
+        };
+        (depth == 0 || descend(&self.options, depth - 1))
+            && (self.options.hidden || !is_hidden(path))
+            && self.filter.accepts(path)
+    }
+
+    /// Whether the source picks images from `dir`
+    pub fn is_dir(&self, dir: &Path) -> bool {
+        if self.dir == dir {
+            return true;
+        }
+        match (self.dir.canonicalize(), dir.canonicalize()) {
+            (Ok(ours), Ok(theirs)) => ours == theirs,
+            _ => false,
+        }
+    }
+
+    /// The settings the source ended up with
+    pub fn spec(&self) -> SourceSpec {
+        SourceSpec {
+            path: self.dir.clone(),
+            recursive: self.options.recursive.then_some(true),
+            max_depth: self.options.max_depth,
+            hidden: self.options.hidden.then_some(true),
+            extensions: Some(self.filter.extensions.clone()),
+            sniff: (!self.filter.sniff).then_some(false),
+        }
+    }
+}
+
+/// Settings for sources that don't bring their own
+#[derive(Debug, Clone)]
+pub struct SourceDefaults {
+    pub options: ScanOptions,
+    pub filter: ImageFilter,
+}
+
+impl SourceDefaults {
+    pub fn source(&self, spec: SourceSpec) -> Source {
+        let extensions = match spec.extensions {
+            Some(extensions) => extensions
+                .iter()
+                .map(|extension| extension.trim_start_matches('.').to_lowercase())
+                .collect(),
+            None => self.filter.extensions.clone(),
+        };
+        Source {
+            dir: spec.path,
+            options: ScanOptions {
+                recursive: spec
+                    .recursive
+                    .unwrap_or(spec.max_depth.is_some() || self.options.recursive),
+                max_depth: spec.max_depth.or(self.options.max_depth),
+                hidden: spec.hidden.unwrap_or(self.options.hidden),
+            },
+            filter: ImageFilter {
+                extensions,
+                sniff: spec.sniff.unwrap_or(self.filter.sniff),
+                supported: self.filter.supported.clone(),
+            },
+        }
+    }
+}
+
+/// Result of [`scan`]
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub files: Vec<PathBuf>,
+    /// Every directory that was visited, including the root
+    pub directories: Vec<PathBuf>,
+}
+
+/// Collects every file in `dir` and the directories they were found in
+///
+/// Symlinks are followed, but every directory is only visited once so
+/// symlink loops can't trap the walker. Only failing to read `dir` itself is
+/// an error, unreadable subdirectories are skipped.
+pub fn scan(dir: &Path, options: &ScanOptions) -> Result<Scan, WallpaperError> {
+    let mut result = Scan::default();
+    let mut visited = HashSet::new();
+    let root = dir.canonicalize().map_err(|e| WallpaperError::io(dir, e))?;
+    visited.insert(root);
+
+    let mut pending = vec![(dir.to_path_buf(), 0)];
+    while let Some((dir, depth)) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if depth == 0 => return Err(WallpaperError::io(dir, e)),
+            Err(e) => {
+                warn!("Skipping {}: {e}", dir.to_string_lossy());
+                continue;
+            }
+        };
+        result.directories.push(dir.clone());
+
+        for entry in entries.filter_map(|res| res.ok()) {
+            let path = entry.path();
+            if !options.hidden && is_hidden(&path) {
+                trace!("Skipping hidden {}", path.to_string_lossy());
+                continue;
+            }
+
+            // Follows symlinks, broken ones are skipped
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_file() {
+                result.files.push(path);
+            } else if metadata.is_dir() && descend(options, depth) {
+                match path.canonicalize() {
+                    Ok(real) => {
+                        if visited.insert(real) {
+                            pending.push((path, depth + 1));
+                        } else {
+                            trace!("Already visited {}", path.to_string_lossy());
+                        }
+                    }
+                    Err(e) => warn!("Skipping {}: {e}", path.to_string_lossy()),
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn descend(options: &ScanOptions, depth: usize) -> bool {
+    options.recursive && options.max_depth.is_none_or(|max| depth < max)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(recursive: bool, max_depth: Option<usize>, hidden: bool) -> ScanOptions {
+        ScanOptions {
+            recursive,
+            max_depth,
+            hidden,
+        }
+    }
+
+    /// A directory tree under the system's temporary directory, removed again
+    /// on drop
+    struct Tree(PathBuf);
+
+    impl Tree {
+        /// ```text
+        /// a.png
+        /// .hidden.png
+        /// .dir/b.png
+        /// sub/c.png
+        /// sub/deep/d.png
+        /// sub/deep/loop -> the root
+        /// ```
+        fn new(name: &str) -> Self {
+            let root =
+                std::env::temp_dir().join(format!("wallpaperd-scan-{}-{name}", std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            for dir in [".dir", "sub/deep"] {
+                fs::create_dir_all(root.join(dir)).unwrap();
+            }
+            for file in [
+                "a.png",
+                ".hidden.png",
+                ".dir/b.png",
+                "sub/c.png",
+                "sub/deep/d.png",
+            ] {
+                fs::write(root.join(file), "").unwrap();
+            }
+            std::os::unix::fs::symlink(&root, root.join("sub/deep/loop")).unwrap();
+            Tree(root)
+        }
+
+        fn scan(&self, recursive: bool, max_depth: Option<usize>, hidden: bool) -> Vec<String> {
+            let mut files: Vec<_> = scan(&self.0, &options(recursive, max_depth, hidden))
+                .unwrap()
+                .files
+                .iter()
+                .map(|file| {
+                    let relative = file.strip_prefix(&self.0).unwrap();
+                    relative.to_string_lossy().into_owned()
+                })
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn max_depth() {
+        let tree = Tree::new("depth");
+        assert_eq!(tree.scan(false, None, false), ["a.png"]);
+        assert_eq!(tree.scan(true, Some(0), false), ["a.png"]);
+        assert_eq!(tree.scan(true, Some(1), false), ["a.png", "sub/c.png"]);
+        assert_eq!(
+            tree.scan(true, Some(2), false),
+            ["a.png", "sub/c.png", "sub/deep/d.png"]
+        );
+    }
+
+    #[test]
+    fn hidden_files() {
+        let tree = Tree::new("hidden");
+        assert_eq!(tree.scan(false, None, true), [".hidden.png", "a.png"]);
+        assert_eq!(
+            tree.scan(true, None, true),
+            [
+                ".dir/b.png",
+                ".hidden.png",
+                "a.png",
+                "sub/c.png",
+                "sub/deep/d.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn symlink_loop() {
+        let tree = Tree::new("loop");
+        // The link leads back to the root, which was visited already
+        assert_eq!(
+            tree.scan(true, None, false),
+            ["a.png", "sub/c.png", "sub/deep/d.png"]
+        );
+        assert_eq!(
+            scan(&tree.0, &options(true, None, false))
+                .unwrap()
+                .directories
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn missing_dir() {
+        let tree = Tree::new("missing");
+        assert!(scan(&tree.0.join("nothing"), &options(true, None, false)).is_err());
+    }
+}
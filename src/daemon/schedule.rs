@@ -0,0 +1,630 @@
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use serde::Deserialize;
+use std::{ops::RangeInclusive, path::PathBuf, time::Duration};
+
+use common::{ActiveRule, NextImage, Transition, WallpaperError};
+
+use crate::calendar::{Days, Holiday};
+use crate::sun::Sun;
+
+/// How many days ahead transitions are looked for
+const LOOKAHEAD_DAYS: i64 = 7;
+/// Calendar rules change rarely, so they are looked for a year ahead
+const CALENDAR_LOOKAHEAD_DAYS: i64 = 366;
+
+/// A schedule rule as written in the config file
+///
+/// ```toml
+/// [[schedule]]
+/// name = "work"
+/// from = "09:00"
+/// to = "17:00"
+/// days = ["mon", "tue", "wed", "thu", "fri"]
+/// mode = "static"
+/// image = "/usr/share/backgrounds/company.png"
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    name: Option<String>,
+    /// `HH:MM`, the rule ends at `to` on the next day if it is earlier
+    from: String,
+    to: String,
+    /// Days the rule starts on, every day if not given
+    days: Option<Vec<String>>,
+    collection: Option<String>,
+    mode: Option<NextImage>,
+    /// Image to show in static mode
+    image: Option<PathBuf>,
+    /// Seconds between wallpaper changes
+    interval: Option<u64>,
+    /// Rules with a higher priority win, the later one among equals
+    #[serde(default)]
+    priority: i64,
+}
+
+/// A rule for whole days as written in the config file
+///
+/// The days are given by exactly one of `months`, `from` or `holiday`.
+///
+/// ```toml
+/// [[calendar]]
+/// name = "winter"
+/// months = ["dec-feb"]
+/// collection = "snow"
+///
+/// [[calendar]]
+/// name = "christmas"
+/// from = "12-24"
+/// to = "12-26"
+/// priority = 10
+/// mode = "static"
+/// image = "/home/me/tree.png"
+///
+/// [[calendar]]
+/// holiday = "easter"
+/// offset = -2
+/// length = 4
+/// collection = "eggs"
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CalendarConfig {
+    name: Option<String>,
+    /// Month names or ranges like `dec-feb`
+    months: Option<Vec<String>>,
+    /// `MM-DD` for every year, `YYYY-MM-DD` for a single one
+    from: Option<String>,
+    /// Last day, the same as `from` if not given
+    to: Option<String>,
+    holiday: Option<Holiday>,
+    /// Days between the holiday and the first day of the rule
+    offset: Option<i64>,
+    /// Number of days the rule lasts, starting with the holiday
+    length: Option<i64>,
+    collection: Option<String>,
+    mode: Option<NextImage>,
+    image: Option<PathBuf>,
+    interval: Option<u64>,
+    #[serde(default)]
+    priority: i64,
+}
+
+/// The settings schedule rules can change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scheduled {
+    pub collection: Option<String>,
+    pub mode: NextImage,
+    /// Image shown in static mode
+    pub image: Option<PathBuf>,
+    pub interval: Duration,
+}
+
+/// When a rule is active
+#[derive(Debug, Clone)]
+enum Period {
+    /// Between two times of day
+    Daily {
+        from: NaiveTime,
+        to: NaiveTime,
+        /// Empty for every day
+        days: Vec<Weekday>,
+    },
+    /// On whole days
+    Calendar(Days),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    period: Period,
+    priority: i64,
+    collection: Option<String>,
+    mode: Option<NextImage>,
+    image: Option<PathBuf>,
+    interval: Option<Duration>,
+}
+
+/// Checks the settings a rule changes
+fn check_settings(
+    mode: Option<NextImage>,
+    image: &Option<PathBuf>,
+    interval: Option<u64>,
+) -> Result<(), String> {
+    if image.is_some() && mode != Some(NextImage::Static) {
+        return Err("an image needs mode = \"static\"".to_string());
+    }
+    if interval == Some(0) {
+        return Err("the interval must be at least one second".to_string());
+    }
+    Ok(())
+}
+
+impl Rule {
+    fn new(index: usize, config: RuleConfig) -> Result<Self, String> {
+        let name = config.name.unwrap_or_else(|| format!("rule {}", index + 1));
+        check_settings(config.mode, &config.image, config.interval)
+            .map_err(|e| format!("{name}: {e}"))?;
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|e| format!("{name}: invalid time {time:?}: {e}"))
+        };
+        let days = config
+            .days
+            .unwrap_or_default()
+            .iter()
+            .map(|day| {
+                day.parse()
+                    .map_err(|_| format!("{name}: invalid day {day:?}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rule {
+            period: Period::Daily {
+                from: time(&config.from)?,
+                to: time(&config.to)?,
+                days,
+            },
+            priority: config.priority,
+            collection: config.collection,
+            mode: config.mode,
+            image: config.image,
+            interval: config.interval.map(Duration::from_secs),
+            name,
+        })
+    }
+
+    fn calendar(index: usize, config: CalendarConfig) -> Result<Self, String> {
+        let name = config
+            .name
+            .unwrap_or_else(|| format!("calendar rule {}", index + 1));
+        let days = match (&config.months, &config.from, config.holiday) {
+            (Some(months), None, None) => Days::months(months),
+            (None, Some(from), None) => Days::dates(from, config.to.as_deref().unwrap_or(from)),
+            (None, None, Some(holiday)) => match config.length.unwrap_or(1) {
+                length if length < 1 => Err("the length must be at least one day".to_string()),
+                length => Ok(Days::Holiday {
+                    holiday,
+                    offset: config.offset.unwrap_or(0),
+                    length,
+                }),
+            },
+            _ => Err("needs exactly one of months, from and holiday".to_string()),
+        }
+        .and_then(|days| {
+            if config.to.is_some() && config.from.is_none() {
+                return Err("to needs from".to_string());
+            }
+            if (config.offset.is_some() || config.length.is_some()) && config.holiday.is_none() {
+                return Err("offset and length need a holiday".to_string());
+            }
+            check_settings(config.mode, &config.image, config.interval)?;
+            Ok(days)
+        })
+        .map_err(|e| format!("{name}: {e}"))?;
+        Ok(Rule {
+            period: Period::Calendar(days),
+            priority: config.priority,
+            collection: config.collection,
+            mode: config.mode,
+            image: config.image,
+            interval: config.interval.map(Duration::from_secs),
+            name,
+        })
+    }
+
+    /// Start and end of the rule if it starts on `date`
+    fn window(&self, date: NaiveDate) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        match &self.period {
+            Period::Daily { from, to, days } => {
+                if !days.is_empty() && !days.contains(&date.weekday()) {
+                    return None;
+                }
+                // Rules ending at or before their start end on the next day
+                let end_date = if to > from { date } else { date.succ_opt()? };
+                Some((date.and_time(*from), end_date.and_time(*to)))
+            }
+            Period::Calendar(days) => {
+                // Consecutive days make up a single window
+                if !days.contains(date) || date.pred_opt().is_some_and(|pred| days.contains(pred)) {
+                    return None;
+                }
+                let end = date
+                    .iter_days()
+                    .skip(1)
+                    .take(CALENDAR_LOOKAHEAD_DAYS as usize)
+                    .find(|&day| !days.contains(day))?;
+                Some((date.into(), end.into()))
+            }
+        }
+    }
+
+    fn is_active(&self, now: NaiveDateTime) -> bool {
+        match &self.period {
+            // A window that started yesterday might still be open
+            Period::Daily { .. } => [now.date().pred_opt(), Some(now.date())]
+                .into_iter()
+                .flatten()
+                .filter_map(|date| self.window(date))
+                .any(|(start, end)| start <= now && now < end),
+            Period::Calendar(days) => days.contains(now.date()),
+        }
+    }
+
+    /// Days relative to today the windows still open or coming up start on
+    fn search_days(&self) -> RangeInclusive<i64> {
+        match self.period {
+            Period::Daily { .. } => -1..=LOOKAHEAD_DAYS,
+            Period::Calendar(_) => -CALENDAR_LOOKAHEAD_DAYS..=CALENDAR_LOOKAHEAD_DAYS,
+        }
+    }
+
+    /// What [`Rule::apply`] changes, for humans
+    fn changes(&self) -> Vec<String> {
+        let mut changes = Vec::new();
+        if let Some(collection) = &self.collection {
+            changes.push(format!("collection {collection}"));
+        }
+        match (self.mode, &self.image) {
+            (Some(mode), Some(image)) => {
+                changes.push(format!("mode {mode:?} {}", image.to_string_lossy()))
+            }
+            (Some(mode), None) => changes.push(format!("mode {mode:?}")),
+            (None, _) => {}
+        }
+        if let Some(interval) = self.interval {
+            changes.push(format!("interval {}s", interval.as_secs()));
+        }
+        changes
+    }
+
+    fn apply(&self, settings: &mut Scheduled) {
+        if let Some(collection) = &self.collection {
+            settings.collection = Some(collection.clone());
+        }
+        if let Some(mode) = self.mode {
+            settings.mode = mode;
+            settings.image = self.image.clone();
+        }
+        if let Some(interval) = self.interval {
+            settings.interval = interval;
+        }
+    }
+}
+
+/// Rules from the config file and what they changed
+#[derive(Debug, Clone, Default)]
+pub struct Schedule {
+    /// Calendar rules first, so time of day rules win among equal priorities
+    rules: Vec<Rule>,
+    /// Switches between day and night
+    sun: Option<Sun>,
+    /// Indices of the rules that were active when last applied, in the order
+    /// they were applied
+    active: Vec<usize>,
+    /// Whether it was night when last applied
+    night: bool,
+    /// Settings from before the schedule changed anything, restored once it
+    /// doesn't anymore
+    base: Option<Scheduled>,
+    /// The rules were replaced, so they have to be applied again
+    replaced: bool,
+}
+
+impl Schedule {
+    pub fn new(
+        rules: Vec<RuleConfig>,
+        calendar: Vec<CalendarConfig>,
+        sun: Option<Sun>,
+    ) -> Result<Self, WallpaperError> {
+        let calendar = calendar
+            .into_iter()
+            .enumerate()
+            .map(|(index, rule)| Rule::calendar(index, rule));
+        let rules = rules
+            .into_iter()
+            .enumerate()
+            .map(|(index, rule)| Rule::new(index, rule));
+        let rules = calendar
+            .chain(rules)
+            .collect::<Result<_, _>>()
+            .map_err(WallpaperError::Schedule)?;
+        if let Some(sun) = &sun {
+            sun.validate().map_err(WallpaperError::Schedule)?;
+        }
+        Ok(Schedule {
+            rules,
+            sun,
+            ..Default::default()
+        })
+    }
+
+    /// Settings the rules active at `now` ask for, `None` if that's what was
+    /// asked for last time
+    ///
+    /// `current` are the settings in use, they are restored once no rule is
+    /// active anymore. The collection for the time of day comes first, then
+    /// the rules by priority.
+    pub fn evaluate(&mut self, now: NaiveDateTime, current: Scheduled) -> Option<Scheduled> {
+        let mut active: Vec<_> = (0..self.rules.len())
+            .filter(|&idx| self.rules[idx].is_active(now))
+            .collect();
+        // Stable, so later rules still win among equals
+        active.sort_by_key(|&idx| self.rules[idx].priority);
+        let night = self.sun.as_ref().is_some_and(|sun| sun.is_night(now));
+        if active == self.active && night == self.night && !self.replaced {
+            return None;
+        }
+        self.replaced = false;
+
+        let base = self.base.take().unwrap_or(current);
+        let mut settings = base.clone();
+        let sun_collection = self.sun.as_ref().and_then(|sun| sun.collection(night));
+        if let Some(collection) = sun_collection {
+            settings.collection = Some(collection.clone());
+        }
+        for &idx in &active {
+            self.rules[idx].apply(&mut settings);
+        }
+        if !active.is_empty() || sun_collection.is_some() {
+            self.base = Some(base);
+        }
+        self.active = active;
+        self.night = night;
+        Some(settings)
+    }
+
+    /// Takes over the rules of another schedule, keeping the settings to
+    /// restore once no rule is active anymore
+    pub fn replace(&mut self, schedule: Schedule) {
+        self.rules = schedule.rules;
+        self.sun = schedule.sun;
+        self.replaced = true;
+    }
+
+    /// Whether it was night when last evaluated
+    pub fn is_night(&self) -> bool {
+        self.night
+    }
+
+    pub fn sun(&self) -> Option<&Sun> {
+        self.sun.as_ref()
+    }
+
+    /// Rules that were active when last evaluated, the one that wins first
+    pub fn active_rules(&self) -> Vec<ActiveRule> {
+        let rules = self.active.iter().rev().map(|&idx| {
+            let rule = &self.rules[idx];
+            ActiveRule {
+                name: rule.name.clone(),
+                priority: Some(rule.priority),
+                changes: rule.changes(),
+            }
+        });
+        let sun = self.sun.as_ref().and_then(|sun| {
+            let collection = sun.collection(self.night)?;
+            Some(ActiveRule {
+                name: if self.night { "night" } else { "day" }.to_string(),
+                priority: None,
+                changes: vec![format!("collection {collection}")],
+            })
+        });
+        rules.chain(sun).collect()
+    }
+
+    /// The next `count` times a rule starts or ends after `now`
+    ///
+    /// Sunsets are listed as the start of a rule called night.
+    pub fn transitions(&self, now: NaiveDateTime, count: usize) -> Vec<Transition> {
+        self.upcoming(now)
+            .into_iter()
+            .take(count)
+            .map(|(time, rule, start)| Transition {
+                time: time.format("%a %Y-%m-%d %H:%M").to_string(),
+                in_secs: (time - now).num_seconds().max(0) as u64,
+                rule: rule.to_string(),
+                start,
+            })
+            .collect()
+    }
+
+    /// How long until the next rule starts or ends
+    pub fn until_next_transition(&self, now: NaiveDateTime) -> Option<Duration> {
+        let (time, _, _) = self.upcoming(now).into_iter().next()?;
+        (time - now).to_std().ok()
+    }
+
+    /// Starts (`true`) and ends of rules after `now`, sorted by time
+    fn upcoming(&self, now: NaiveDateTime) -> Vec<(NaiveDateTime, &str, bool)> {
+        let dates = |days: RangeInclusive<i64>| {
+            days.filter_map(move |days| now.date().checked_add_signed(ChronoDuration::days(days)))
+        };
+        let rules = self.rules.iter().flat_map(|rule| {
+            dates(rule.search_days()).filter_map(move |date| {
+                let (start, end) = rule.window(date)?;
+                Some([
+                    (start, rule.name.as_str(), true),
+                    (end, rule.name.as_str(), false),
+                ])
+            })
+        });
+        let sun = dates(-1..=LOOKAHEAD_DAYS).flat_map(|date| {
+            self.sun
+                .iter()
+                .flat_map(move |sun| sun.events(date))
+                .map(|(time, sunset)| (time, "night", sunset))
+        });
+
+        let mut upcoming: Vec<_> = rules
+            .flatten()
+            .chain(sun)
+            .filter(|(time, _, _)| *time > now)
+            .collect();
+        upcoming.sort_by_key(|(time, _, _)| *time);
+        upcoming
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn rule(name: &str, from: &str, to: &str, days: &[&str], priority: i64) -> RuleConfig {
+        RuleConfig {
+            name: Some(name.to_string()),
+            from: from.to_string(),
+            to: to.to_string(),
+            days: (!days.is_empty()).then(|| days.iter().map(|day| day.to_string()).collect()),
+            collection: Some(name.to_string()),
+            mode: None,
+            image: None,
+            interval: None,
+            priority,
+        }
+    }
+
+    fn schedule(rules: Vec<RuleConfig>) -> Schedule {
+        Schedule::new(rules, Vec::new(), None).unwrap()
+    }
+
+    fn base() -> Scheduled {
+        Scheduled {
+            collection: None,
+            mode: NextImage::Linear,
+            image: None,
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    fn collection(settings: Option<Scheduled>) -> Option<String> {
+        settings.unwrap().collection
+    }
+
+    /// The collection asked for at `at`, empty for none
+    fn collection_at(schedule: &mut Schedule, at: &str) -> String {
+        collection(schedule.evaluate(time(at), base())).unwrap_or_default()
+    }
+
+    const WEEKDAYS: &[&str] = &["mon", "tue", "wed", "thu", "fri"];
+
+    #[test]
+    fn window_crosses_midnight() {
+        let night = Rule::new(0, rule("night", "20:00", "06:00", &[], 0)).unwrap();
+        let monday = time("2024-06-03 00:00").date();
+        assert_eq!(
+            night.window(monday),
+            Some((time("2024-06-03 20:00"), time("2024-06-04 06:00")))
+        );
+
+        let work = Rule::new(0, rule("work", "09:00", "17:00", WEEKDAYS, 0)).unwrap();
+        assert_eq!(
+            work.window(monday),
+            Some((time("2024-06-03 09:00"), time("2024-06-03 17:00")))
+        );
+        assert_eq!(work.window(time("2024-06-08 00:00").date()), None);
+    }
+
+    #[test]
+    fn active_since_yesterday() {
+        let friday_night = Rule::new(0, rule("night", "20:00", "06:00", &["fri"], 0)).unwrap();
+        assert!(friday_night.is_active(time("2024-06-07 23:00")));
+        assert!(friday_night.is_active(time("2024-06-08 02:00")));
+        assert!(!friday_night.is_active(time("2024-06-08 06:00")));
+        // Saturday isn't one of its days
+        assert!(!friday_night.is_active(time("2024-06-08 21:00")));
+        assert!(!friday_night.is_active(time("2024-06-07 19:59")));
+    }
+
+    #[test]
+    fn evaluate_by_priority() {
+        let mut schedule = schedule(vec![
+            rule("high", "08:00", "12:00", &[], 1),
+            rule("low", "08:00", "18:00", &[], 0),
+            rule("later", "10:00", "18:00", &[], 0),
+        ]);
+        assert_eq!(
+            collection(schedule.evaluate(time("2024-06-03 09:00"), base())),
+            Some("high".to_string())
+        );
+        assert_eq!(schedule.evaluate(time("2024-06-03 09:30"), base()), None);
+        // Still the higher priority
+        assert_eq!(
+            collection(schedule.evaluate(time("2024-06-03 11:00"), base())),
+            Some("high".to_string())
+        );
+        // The later rule among equals
+        assert_eq!(
+            collection(schedule.evaluate(time("2024-06-03 13:00"), base())),
+            Some("later".to_string())
+        );
+        let names: Vec<_> = schedule
+            .active_rules()
+            .into_iter()
+            .map(|rule| rule.name)
+            .collect();
+        assert_eq!(names, ["later", "low"]);
+    }
+
+    #[test]
+    fn evaluate_restores_base() {
+        let mut schedule = schedule(vec![RuleConfig {
+            mode: Some(NextImage::Static),
+            image: Some(PathBuf::from("/tmp/lunch.png")),
+            interval: Some(600),
+            ..rule("lunch", "12:00", "13:00", &[], 0)
+        }]);
+        assert_eq!(schedule.evaluate(time("2024-06-03 11:00"), base()), None);
+
+        let lunch = schedule.evaluate(time("2024-06-03 12:00"), base()).unwrap();
+        assert_eq!(lunch.mode, NextImage::Static);
+        assert_eq!(lunch.image, Some(PathBuf::from("/tmp/lunch.png")));
+        assert_eq!(lunch.interval, Duration::from_secs(600));
+
+        // The settings in use while the rule was active don't matter
+        assert_eq!(
+            schedule.evaluate(time("2024-06-03 13:00"), lunch),
+            Some(base())
+        );
+    }
+
+    #[test]
+    fn weekdays_and_evenings() {
+        let mut schedule = schedule(vec![
+            rule("work", "09:00", "17:00", WEEKDAYS, 0),
+            rule("evening", "20:00", "07:00", &[], 0),
+        ]);
+        assert_eq!(collection_at(&mut schedule, "2024-06-07 10:00"), "work");
+        assert_eq!(collection_at(&mut schedule, "2024-06-07 18:00"), "");
+        assert_eq!(collection_at(&mut schedule, "2024-06-07 23:00"), "evening");
+        assert_eq!(schedule.evaluate(time("2024-06-08 03:00"), base()), None);
+        // No work on Saturday
+        assert_eq!(collection_at(&mut schedule, "2024-06-08 10:00"), "");
+
+        let upcoming = schedule.upcoming(time("2024-06-07 18:00"));
+        assert_eq!(
+            upcoming[..4],
+            [
+                (time("2024-06-07 20:00"), "evening", true),
+                (time("2024-06-08 07:00"), "evening", false),
+                (time("2024-06-08 20:00"), "evening", true),
+                (time("2024-06-09 07:00"), "evening", false),
+            ]
+        );
+        // The window that started yesterday ends first
+        assert_eq!(
+            schedule.upcoming(time("2024-06-10 06:00"))[..3],
+            [
+                (time("2024-06-10 07:00"), "evening", false),
+                (time("2024-06-10 09:00"), "work", true),
+                (time("2024-06-10 17:00"), "work", false),
+            ]
+        );
+        assert_eq!(
+            schedule.until_next_transition(time("2024-06-07 18:00")),
+            Some(Duration::from_secs(2 * 60 * 60))
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    cmp::Ordering,
+    fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use common::{SortKey, SortOrder};
+
+/// Sorts the images the way linear mode walks through them
+///
+/// Images with the same key are ordered by name, images whose metadata
+/// can't be read go first.
+pub fn sort(images: &mut [PathBuf], order: SortOrder) {
+    images.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+    match order.key {
+        SortKey::Name => {}
+        key => images.sort_by_cached_key(|path| sort_key(path, key)),
+    }
+    if order.reverse {
+        images.reverse();
+    }
+}
+
+/// Puts `image` into the sorted `images` where [`sort`] would have put it
+pub fn insert(images: &mut Vec<PathBuf>, image: PathBuf, order: SortOrder) {
+    let key = sort_key(&image, order.key);
+    let name = image.to_string_lossy();
+    let before = if order.reverse {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    };
+    let idx = images.partition_point(|other| {
+        sort_key(other, order.key)
+            .cmp(&key)
+            .then_with(|| natural_cmp(&other.to_string_lossy(), &name))
+            == before
+    });
+    images.insert(idx, image);
+}
+
+/// What images are ordered by before their name, `None` if the metadata
+/// can't be read
+fn sort_key(path: &Path, key: SortKey) -> Option<(i64, i64)> {
+    match key {
+        SortKey::Name => Some((0, 0)),
+        SortKey::Mtime => metadata(path).map(|metadata| (metadata.mtime(), metadata.mtime_nsec())),
+        SortKey::Ctime => metadata(path).map(|metadata| (metadata.ctime(), metadata.ctime_nsec())),
+        SortKey::Size => {
+            metadata(path).map(|metadata| (i64::try_from(metadata.len()).unwrap_or(i64::MAX), 0))
+        }
+    }
+}
+
+fn metadata(path: &Path) -> Option<fs::Metadata> {
+    fs::metadata(path).ok()
+}
+
+/// Compares strings like a human would, "img2" comes before "img10"
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                // Without leading zeros the longer number is the bigger one
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x
+                    .to_lowercase()
+                    .cmp(y.to_lowercase())
+                    .then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+        number.push(c);
+    }
+    number.trim_start_matches('0').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn numbers_by_value() {
+        assert_eq!(natural_cmp("img2", "img10"), Ordering::Less);
+        assert_eq!(natural_cmp("img10", "img9"), Ordering::Greater);
+        assert_eq!(natural_cmp("img2b", "img2a"), Ordering::Greater);
+    }
+
+    #[test]
+    fn leading_zeros() {
+        assert_eq!(natural_cmp("img007", "img8"), Ordering::Less);
+        assert_eq!(natural_cmp("img010", "img9"), Ordering::Greater);
+        assert_eq!(natural_cmp("img0", "img00"), Ordering::Equal);
+    }
+
+    #[test]
+    fn case_folding() {
+        assert_eq!(natural_cmp("apple", "Banana"), Ordering::Less);
+        assert_eq!(natural_cmp("Img1", "img2"), Ordering::Less);
+        // Only the case tells them apart
+        assert_eq!(natural_cmp("A", "a"), Ordering::Less);
+    }
+
+    #[test]
+    fn insert_like_sort() {
+        for reverse in [false, true] {
+            let order = SortOrder {
+                key: SortKey::Name,
+                reverse,
+            };
+            let mut sorted = paths(&["b.png", "img10.png", "Img1.png", "a.png", "img2.png"]);
+            sort(&mut sorted, order);
+            let mut inserted = Vec::new();
+            for image in paths(&["img2.png", "a.png", "img10.png", "b.png", "Img1.png"]) {
+                insert(&mut inserted, image, order);
+            }
+            assert_eq!(inserted, sorted);
+        }
+    }
+
+    #[test]
+    fn sort_by_name() {
+        let mut sorted = paths(&["img10.png", "img2.png", "Img1.png"]);
+        sort(
+            &mut sorted,
+            SortOrder {
+                key: SortKey::Name,
+                reverse: false,
+            },
+        );
+        assert_eq!(sorted, paths(&["Img1.png", "img2.png", "img10.png"]));
+    }
+}
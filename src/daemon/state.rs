@@ -1,17 +1,36 @@
 #![warn(missing_docs)]
-use clap::clap_derive::ArgEnum;
-use log::{error, info, trace, warn};
-use rand::Rng;
+use chrono::Local;
+use common::{
+    ActiveRule, Collection, Event, Filters, NextImage, Rating, SortOrder, SourceSpec,
+    StateSnapshot, Status, SunTimes, Transition, WallpaperError, MAX_INTERVAL,
+};
+use log::{info, trace, warn};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    seq::SliceRandom,
+    Rng,
+};
 use std::{
-    collections::VecDeque,
-    fs,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     io::{Read, Write},
     os::unix::net::UnixStream,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::Command,
-    time::Duration,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Condvar,
+    },
+    time::{Duration, Instant},
 };
 
+use crate::database::Database;
+use crate::patterns::Patterns;
+use crate::persist::SavedState;
+use crate::playlist;
+use crate::scan::{Source, SourceDefaults};
+use crate::schedule::{Schedule, Scheduled};
+use crate::sort::{self, sort};
+use crate::sun::{dark_variant, light_variant};
 use crate::WallpaperMethod;
 
 #[derive(Debug)]
@@ -22,6 +41,14 @@ struct History {
 }
 
 impl History {
+    fn new(current: PathBuf, history_max_size: usize) -> Self {
+        History {
+            previous: VecDeque::from([current]),
+            next: Vec::new(),
+            history_max_size,
+        }
+    }
+
     fn has_next(&self) -> bool {
         !self.next.is_empty()
     }
@@ -48,6 +75,29 @@ impl History {
         }
     }
 
+    /// Remove every occurrence of `path`, except for the current image
+    fn forget(&mut self, path: &Path) {
+        self.retain(|image| image != path);
+    }
+
+    /// Keep only the images `keep` returns true for, and the current one
+    fn retain(&mut self, mut keep: impl FnMut(&Path) -> bool) {
+        self.next.retain(|image| keep(image));
+        if let Some(current) = self.previous.pop_back() {
+            self.previous.retain(|image| keep(image));
+            self.previous.push_back(current);
+        }
+    }
+
+    /// Change the maximum size, forgetting the oldest images if needed
+    fn resize(&mut self, history_max_size: usize) {
+        self.history_max_size = history_max_size;
+        // The current image always stays
+        while self.previous.len() > history_max_size.max(1) {
+            self.previous.pop_front();
+        }
+    }
+
     fn push_back(&mut self, path: PathBuf) {
         if self.previous.len() >= self.history_max_size {
             self.previous.pop_front();
@@ -63,18 +113,64 @@ pub struct State {
     action: NextImage,
     previous_action: NextImage,
     change_interval: Duration,
-    image_dir: PathBuf,
+    /// Directories images are picked from, each one has its own watcher
+    sources: Vec<Arc<Source>>,
+    /// Settings for sources added at runtime
+    source_defaults: SourceDefaults,
+    /// Which images of the sources may be shown
+    patterns: Patterns,
+    /// Playlist shown instead of the images in the sources
+    playlist: Option<PathBuf>,
+    /// Only images with this tag are shown
+    collection: Option<String>,
+    /// Histories of the collections not in use, to resume them later
+    histories: HashMap<Option<String>, History>,
+    schedule: Schedule,
     use_fallback: bool,
     default_image: PathBuf,
     wallpaper_cmd: WallpaperMethod,
-    recusive: bool,
+    next_change: Option<Instant>,
+    /// Wakes up the timer thread when it has to look at `next_change` again
+    timer: Arc<Condvar>,
+    /// Time that was left until the next change when the timer was paused
+    paused: Option<Duration>,
+    subscribers: Vec<Sender<Event>>,
+    /// Set while the default image is shown because there was nothing else
+    no_images: bool,
+    /// The images that can be shown, the ones of the collection in use
+    images: Vec<PathBuf>,
+    /// Every image of the sources, kept up to date by the watchers
+    all_images: Vec<PathBuf>,
+    /// Why the last scan couldn't read any of the sources
+    scan_error: Option<WallpaperError>,
+    sort_order: SortOrder,
+    /// Images not shown yet in the current shuffle cycle, taken from the back
+    shuffle_bag: Vec<PathBuf>,
+    /// Where in `images` the last image was picked, playlists can list an
+    /// image more than once
+    picked: Option<usize>,
+    database: Database,
+    /// Where the state is saved on every change, `None` to not save it
+    state_file: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Copy, ArgEnum)]
-pub enum NextImage {
-    Random,
-    Linear,
-    Static,
+/// Settings the state starts out with
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub change_interval: Duration,
+    pub default_image: PathBuf,
+    pub action: NextImage,
+    /// The saved mode and interval are only restored if these are `false`
+    pub explicit_action: bool,
+    pub explicit_interval: bool,
+    pub history_max_size: usize,
+    pub sort_order: SortOrder,
+    pub source_defaults: SourceDefaults,
+    pub patterns: Patterns,
+    pub playlist: Option<PathBuf>,
+    pub schedule: Schedule,
+    /// Restore the state from this file and keep it up to date
+    pub state_file: Option<PathBuf>,
 }
 
 pub enum ChangeImageDirection {
@@ -84,44 +180,170 @@ pub enum ChangeImageDirection {
 
 impl State {
     pub fn new(
-        change_interval: Duration,
-        image_dir: PathBuf,
-        default_image: PathBuf,
-        action: NextImage,
+        settings: Settings,
+        sources: Vec<Source>,
         wallpaper_cmd: WallpaperMethod,
-        history_max_size: usize,
-        recusive: bool,
+        database: Database,
     ) -> Self {
-        let mut history = VecDeque::new();
-        history.push_back(default_image.clone());
+        let (explicit_action, explicit_interval) =
+            (settings.explicit_action, settings.explicit_interval);
+        let mut state = State::idle(settings, sources, wallpaper_cmd, database);
+        let restored = state
+            .state_file
+            .as_deref()
+            .and_then(SavedState::load)
+            .is_some_and(|saved| state.restore(saved, explicit_action, explicit_interval));
+        let _ = state.rescan();
+        if let Some(reason) = state.missing_images() {
+            // Show the default right away instead of waiting for the timer
+            warn!("{reason}, showing the default image");
+            state.no_images = true;
+            if *state.get_current_image() != state.default_image {
+                state.history.push_back(state.default_image.clone());
+            }
+            if let Err(e) = state.update() {
+                warn!("{e}");
+            }
+        } else if restored {
+            info!("Showing the wallpaper from last time");
+            if let Err(e) = state.update() {
+                warn!("{e}");
+            }
+        }
+        state
+    }
 
+    /// The state before anything was restored, scanned or shown
+    fn idle(
+        settings: Settings,
+        sources: Vec<Source>,
+        wallpaper_cmd: WallpaperMethod,
+        database: Database,
+    ) -> Self {
+        let Settings {
+            change_interval,
+            default_image,
+            action,
+            history_max_size,
+            sort_order,
+            source_defaults,
+            patterns,
+            playlist,
+            schedule,
+            state_file,
+            ..
+        } = settings;
         State {
-            history: History {
-                previous: history,
-                next: Vec::new(),
-                history_max_size,
-            },
+            history: History::new(default_image.clone(), history_max_size),
             action,
             previous_action: action,
             change_interval,
-            image_dir,
+            sources: sources.into_iter().map(Arc::new).collect(),
+            source_defaults,
+            patterns,
+            playlist,
+            collection: None,
+            histories: HashMap::new(),
+            schedule,
             use_fallback: false,
             default_image,
             wallpaper_cmd,
-            recusive,
+            next_change: None,
+            timer: Arc::new(Condvar::new()),
+            paused: None,
+            subscribers: Vec::new(),
+            no_images: false,
+            images: Vec::new(),
+            all_images: Vec::new(),
+            scan_error: None,
+            sort_order,
+            shuffle_bag: Vec::new(),
+            picked: None,
+            database,
+            state_file,
         }
     }
 
-    pub fn change_image(&mut self, direction: ChangeImageDirection) {
+    /// Takes over the state saved by the last run, returns whether there is
+    /// a wallpaper from back then to show
+    ///
+    /// A mode or interval set on the command line or in the config file wins
+    /// over the saved one.
+    fn restore(
+        &mut self,
+        saved: SavedState,
+        explicit_action: bool,
+        explicit_interval: bool,
+    ) -> bool {
+        info!("Restoring the state from last time");
+        if !explicit_action {
+            self.action = saved.mode;
+            self.previous_action = saved.previous_mode;
+        }
+        if !explicit_interval && !saved.interval.is_zero() && saved.interval <= MAX_INTERVAL {
+            self.change_interval = saved.interval;
+        }
+        if saved.fallback {
+            self.use_fallback = true;
+            if explicit_action {
+                self.previous_action = self.action;
+                self.action = NextImage::Static;
+            }
+        }
+        self.paused = saved.paused;
+
+        // Images deleted in the meantime can't be shown anymore
+        let mut previous: VecDeque<_> = saved
+            .previous
+            .into_iter()
+            .filter(|image| image.is_file())
+            .collect();
+        while previous.len() > self.history.history_max_size {
+            previous.pop_front();
+        }
+        if previous.is_empty() {
+            return false;
+        }
+        self.history.previous = previous;
+        self.history.next = saved
+            .next
+            .into_iter()
+            .filter(|image| image.is_file())
+            .collect();
+        true
+    }
+
+    /// Saves the state so the next run can pick up where this one stopped
+    pub fn persist(&self) {
+        let path = match &self.state_file {
+            Some(path) => path,
+            None => return,
+        };
+        let saved = SavedState {
+            previous: self.history.previous.iter().cloned().collect(),
+            next: self.history.next.clone(),
+            mode: self.action,
+            previous_mode: self.previous_action,
+            interval: self.change_interval,
+            fallback: self.use_fallback,
+            paused: self.paused,
+        };
+        if let Err(e) = saved.save(path) {
+            warn!("Couldn't save the state: {e}");
+        }
+    }
+
+    pub fn change_image(&mut self, direction: ChangeImageDirection) -> Result<(), WallpaperError> {
         if self.use_fallback {
             info!("Can't change image while using fallback");
-            return;
+            return Err(WallpaperError::FallbackActive);
         }
         if let NextImage::Static = self.action {
             info!("Can't change image while in static mode");
-            return;
+            return Err(WallpaperError::StaticMode);
         }
 
+        let mut picked = false;
         match direction {
             ChangeImageDirection::Next => {
                 info!("Going to the next image");
@@ -129,32 +351,22 @@ impl State {
                 if self.history.has_next() {
                     self.history.go_next();
                 } else {
-                    // If not enough space delete one element
-                    let mut idx = fs::read_dir(&self.image_dir)
-                        .unwrap()
-                        .filter_map(|res| res.ok().map(|e| e.path()))
-                        .position(|elem| elem == *self.history.previous.back().unwrap())
-                        .unwrap_or(0);
-
-                    let num_pics = fs::read_dir(&self.image_dir)
-                        .unwrap()
-                        .filter_map(|res| res.ok().map(|e| e.path()))
-                        .count();
-
-                    if self.action == NextImage::Random {
-                        idx = rand::thread_rng().gen_range(0..num_pics);
-                    } else {
-                        idx += 1;
-                        idx %= num_pics;
+                    if self.images.is_empty() {
+                        // The watcher can't notice a directory that appears
+                        // again after being deleted, so look once more
+                        let _ = self.rescan();
+                    }
+                    if let Some(e) = self.missing_images() {
+                        return self.show_default(e);
+                    }
+                    if self.no_images {
+                        info!("Found images again, resuming");
+                        self.no_images = false;
                     }
 
-                    self.history.push_back(
-                        fs::read_dir(&self.image_dir)
-                            .unwrap()
-                            .filter_map(|res| res.ok().map(|e| e.path()))
-                            .nth(idx)
-                            .unwrap(),
-                    );
+                    let image = self.pick_next();
+                    self.history.push_back(image);
+                    picked = true;
                 }
             }
             ChangeImageDirection::Previous => {
@@ -163,57 +375,792 @@ impl State {
                     self.history.go_previous();
                 } else {
                     info!("There is no previous image");
+                    return Err(WallpaperError::NoPreviousImage);
                 }
             }
         }
 
         // Update current image
-        if self.update().is_err() {
-            error!("Error setting the wallpaper");
+        let result = self.update();
+        if result.is_err() && picked {
+            // It was never shown, going back must not end up on it
+            self.history.previous.pop_back();
+        }
+        result
+    }
+
+    /// Choose the next image according to the mode, `images` must not be empty
+    fn pick_next(&mut self) -> PathBuf {
+        let num_pics = self.images.len();
+        let current = self.get_current_image();
+        let current = match self.picked {
+            Some(idx) if self.images.get(idx) == Some(current) => Some(idx),
+            _ => self.images.iter().position(|elem| elem == current),
+        };
+
+        let idx = match self.action {
+            NextImage::Random => self.pick_weighted(),
+            NextImage::Shuffle => return self.pick_from_bag(),
+            // Start from the beginning if the current image isn't in the directory
+            NextImage::Linear | NextImage::Static => current.map_or(0, |idx| (idx + 1) % num_pics),
+        };
+        self.picked = Some(idx);
+        self.images[idx].clone()
+    }
+
+    /// Pick a random index, higher rated images are more likely
+    fn pick_weighted(&self) -> usize {
+        let weights = self.images.iter().map(|image| {
+            if self.database.is_banned(image) {
+                0
+            } else {
+                weight(self.database.rating(image))
+            }
+        });
+        match WeightedIndex::new(weights) {
+            Ok(distribution) => distribution.sample(&mut rand::thread_rng()),
+            Err(_) => rand::thread_rng().gen_range(0..self.images.len()),
+        }
+    }
+
+    /// Take the next image of the shuffled cycle, starting a new cycle if
+    /// every image was shown
+    fn pick_from_bag(&mut self) -> PathBuf {
+        while let Some(image) = self.shuffle_bag.pop() {
+            // Images might have been deleted or banned since the bag was filled
+            if self.images.contains(&image) && !self.database.is_banned(&image) {
+                return image;
+            }
+        }
+
+        let mut bag = self.shuffle();
+        let image = bag.pop().unwrap();
+        self.shuffle_bag = bag;
+        image
+    }
+
+    /// A new shuffle cycle of every image, the first one to show last
+    fn shuffle(&self) -> Vec<PathBuf> {
+        info!("Shuffling {} images", self.images.len());
+        let mut bag = self.images.clone();
+        bag.retain(|image| !self.database.is_banned(image));
+        bag.shuffle(&mut rand::thread_rng());
+        // Don't show the current image twice in a row at the cycle boundary
+        if bag.len() > 1 && bag.last() == Some(self.get_current_image()) {
+            let last = bag.len() - 1;
+            bag.swap(0, last);
+        }
+        bag
+    }
+
+    /// Why there is nothing to show, if there isn't
+    fn missing_images(&self) -> Option<WallpaperError> {
+        if let Some(e) = &self.scan_error {
+            return Some(e.clone());
+        }
+        if !self.images.is_empty() {
+            return None;
+        }
+        let dirs = match &self.playlist {
+            Some(playlist) => vec![playlist.clone()],
+            None => self
+                .sources
+                .iter()
+                .map(|source| source.dir.clone())
+                .collect(),
+        };
+        Some(WallpaperError::NoImages(dirs))
+    }
+
+    /// Show the default image until the directory contains images again
+    ///
+    /// The timer keeps calling [`State::change_image`], so the rotation
+    /// resumes on its own once images show up.
+    fn show_default(&mut self, reason: WallpaperError) -> Result<(), WallpaperError> {
+        if !self.no_images {
+            warn!("{reason}, showing the default image");
+            self.no_images = true;
+            if *self.get_current_image() != self.default_image {
+                self.history.push_back(self.default_image.clone());
+                self.update()?;
+            }
+        }
+        Err(reason)
+    }
+
+    /// Rebuild the list of images from scratch, returns how many were found
+    ///
+    /// The playlist is read again if one is loaded. Otherwise sources that
+    /// can't be read are skipped, it only fails if none of them could be read.
+    /// The error is kept and reported when the next image is picked, so
+    /// callers that only want the list up to date can ignore it.
+    pub fn rescan(&mut self) -> Result<usize, WallpaperError> {
+        let found = match &self.playlist {
+            Some(playlist) => self.read_playlist(playlist),
+            None => self.scan_sources(),
+        };
+        match found {
+            Ok(mut images) => {
+                // Playlists come in the order they should be shown, repeats
+                // included
+                if self.playlist.is_none() {
+                    // Sources may overlap
+                    let mut seen = HashSet::new();
+                    images.retain(|image| seen.insert(image.clone()));
+                    sort(&mut images, self.sort_order);
+                }
+                self.all_images = images;
+                self.scan_error = None;
+                Ok(self.select_images())
+            }
+            Err(e) => {
+                self.images.clear();
+                self.all_images.clear();
+                self.scan_error = Some(e.clone());
+                Err(e)
+            }
+        }
+    }
+
+    /// Picks the images that can be shown out of the ones found, returns how
+    /// many there are
+    fn select_images(&mut self) -> usize {
+        let images: Vec<_> = self
+            .all_images
+            .iter()
+            .filter(|image| {
+                !self.database.is_banned(image)
+                    && !self.is_dark_variant(image)
+                    && self.in_collection(image)
+            })
+            .cloned()
+            .collect();
+        info!("Found {} images", images.len());
+        self.images = images;
+        // The current cycle goes on, new images join the next one
+        let images: HashSet<_> = self.images.iter().collect();
+        self.shuffle_bag.retain(|image| images.contains(image));
+        self.images.len()
+    }
+
+    fn scan_sources(&self) -> Result<Vec<PathBuf>, WallpaperError> {
+        let mut images = Vec::new();
+        let mut error = None;
+        let mut readable = self.sources.is_empty();
+        for source in &self.sources {
+            info!("Scanning {}", source.dir.to_string_lossy());
+            match source.images() {
+                Ok(found) => {
+                    images.extend(
+                        found
+                            .into_iter()
+                            .filter(|image| self.is_allowed(source, image)),
+                    );
+                    readable = true;
+                }
+                Err(e) => {
+                    // Don't repeat the warning on every retry
+                    if self.scan_error.is_none() {
+                        warn!("{e}");
+                    }
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        match error {
+            Some(e) if !readable => Err(e),
+            _ => Ok(images),
+        }
+    }
+
+    fn read_playlist(&self, playlist: &Path) -> Result<Vec<PathBuf>, WallpaperError> {
+        info!("Reading playlist {}", playlist.to_string_lossy());
+        let entries = playlist::read(playlist).inspect_err(|e| {
+            if self.scan_error.is_none() {
+                warn!("{e}");
+            }
+        })?;
+        let base = playlist.parent().unwrap_or(Path::new(""));
+        Ok(entries
+            .into_iter()
+            .filter(|image| {
+                if !image.is_file() {
+                    info!("Skipping missing {}", image.to_string_lossy());
+                    return false;
+                }
+                // Patterns are relative to the playlist, like its entries
+                let relative = image.strip_prefix(base).unwrap_or(image);
+                self.source_defaults.filter.accepts(image) && self.patterns.allows(relative)
+            })
+            .collect())
+    }
+
+    /// Show the images of a playlist instead of the ones in the sources,
+    /// returns how many were found
+    pub fn load_playlist(&mut self, playlist: PathBuf) -> Result<usize, WallpaperError> {
+        info!("Loading playlist {}", playlist.to_string_lossy());
+        let previous = self.playlist.replace(playlist);
+        self.rescan().inspect_err(|_| {
+            // Keep showing what was shown before
+            self.playlist = previous;
+            let _ = self.rescan();
+        })
+    }
+
+    /// Go back to the images in the sources
+    pub fn unload_playlist(&mut self) -> Result<usize, WallpaperError> {
+        if self.playlist.take().is_none() {
+            return Err(WallpaperError::NoPlaylist);
+        }
+        info!("Unloading playlist");
+        self.rescan()
+    }
+
+    /// Write the order images are shown in, or the history, to a playlist
+    ///
+    /// In shuffle mode that's the rest of the current cycle, followed by the
+    /// images already shown in it.
+    pub fn save_playlist(&mut self, path: &Path, history: bool) -> Result<(), WallpaperError> {
+        info!("Saving playlist {}", path.to_string_lossy());
+        if history {
+            let images: Vec<_> = self.history.previous.iter().cloned().collect();
+            playlist::write(path, &images)
+        } else if self.action == NextImage::Shuffle {
+            // Images might have been deleted or banned since the bag was filled
+            self.shuffle_bag.retain(|image| self.images.contains(image));
+            if self.shuffle_bag.is_empty() {
+                self.shuffle_bag = self.shuffle();
+            }
+            let mut images: Vec<_> = self.shuffle_bag.iter().rev().cloned().collect();
+            images.extend(
+                self.images
+                    .iter()
+                    .filter(|image| !self.shuffle_bag.contains(image))
+                    .cloned(),
+            );
+            playlist::write(path, &images)
+        } else {
+            playlist::write(path, &self.images)
+        }
+    }
+
+    /// Pick images from another directory as well, the caller has to start a
+    /// watcher for the returned source
+    pub fn add_source(&mut self, spec: SourceSpec) -> Result<Arc<Source>, WallpaperError> {
+        if !spec.path.is_dir() {
+            return Err(WallpaperError::NotADirectory(spec.path));
+        }
+        if self.sources.iter().any(|source| source.is_dir(&spec.path)) {
+            return Err(WallpaperError::SourceExists(spec.path));
+        }
+        let source = Arc::new(self.source_defaults.source(spec));
+        info!("Adding source {}", source.dir.to_string_lossy());
+        self.sources.push(source.clone());
+        let _ = self.rescan();
+        Ok(source)
+    }
+
+    /// Stop picking images from `dir`, its watcher notices on its own
+    pub fn remove_source(&mut self, dir: &Path) -> Result<(), WallpaperError> {
+        let idx = match self.sources.iter().position(|source| source.is_dir(dir)) {
+            Some(idx) => idx,
+            None => return Err(WallpaperError::NoSuchSource(dir.to_path_buf())),
+        };
+        let source = self.sources.remove(idx);
+        info!("Removing source {}", source.dir.to_string_lossy());
+        let _ = self.rescan();
+        Ok(())
+    }
+
+    pub fn get_sources(&self) -> Vec<SourceSpec> {
+        self.sources.iter().map(|source| source.spec()).collect()
+    }
+
+    pub fn get_watched_sources(&self) -> Vec<Arc<Source>> {
+        self.sources.clone()
+    }
+
+    /// Whether `source` is still in use, watchers exit once it isn't
+    pub fn has_source(&self, source: &Arc<Source>) -> bool {
+        self.sources.iter().any(|other| Arc::ptr_eq(other, source))
+    }
+
+    /// Add a file that appeared in one of the sources, if it is an image
+    pub fn add_image(&mut self, path: PathBuf) {
+        // Only the playlist decides what's in it
+        if self.playlist.is_some() {
+            return;
+        }
+        if !self.all_images.contains(&path) {
+            if !self
+                .sources
+                .iter()
+                .any(|source| source.accepts(&path) && self.is_allowed(source, &path))
+            {
+                return;
+            }
+            info!("New image {}", path.to_string_lossy());
+            sort::insert(&mut self.all_images, path.clone(), self.sort_order);
+        }
+        if self.in_collection(&path)
+            && !self.is_dark_variant(&path)
+            && !self.images.contains(&path)
+            && !self.database.is_banned(&path)
+        {
+            // Part of the current shuffle cycle, at a random spot
+            let idx = rand::thread_rng().gen_range(0..=self.shuffle_bag.len());
+            self.shuffle_bag.insert(idx, path.clone());
+            sort::insert(&mut self.images, path, self.sort_order);
+        }
+    }
+
+    /// Tags of `image`: the ones it was given and the names of the
+    /// directories between its source and the file
+    fn tags_of(&self, image: &Path) -> BTreeSet<String> {
+        let mut tags: BTreeSet<_> = self.database.tags(image).cloned().collect();
+        let roots: Vec<&Path> = match &self.playlist {
+            Some(playlist) => playlist.parent().into_iter().collect(),
+            None => self
+                .sources
+                .iter()
+                .map(|source| source.dir.as_path())
+                .collect(),
+        };
+        for root in roots {
+            if let Some(dirs) = image.strip_prefix(root).ok().and_then(Path::parent) {
+                tags.extend(dirs.iter().map(|name| name.to_string_lossy().into_owned()));
+            }
+        }
+        tags
+    }
+
+    fn in_collection(&self, image: &Path) -> bool {
+        self.collection
+            .as_ref()
+            .is_none_or(|name| self.tags_of(image).contains(name))
+    }
+
+    pub fn get_tags(&self) -> Vec<String> {
+        self.tags_of(self.get_current_image()).into_iter().collect()
+    }
+
+    pub fn tag(&mut self, image: Option<PathBuf>, tag: String) -> Result<(), WallpaperError> {
+        let image = image.unwrap_or_else(|| self.get_current_image().clone());
+        info!("Tagging {} with {tag}", image.to_string_lossy());
+        self.database.tag(image.clone(), tag)?;
+        // It might belong to the collection in use now
+        self.add_image(image);
+        Ok(())
+    }
+
+    pub fn untag(&mut self, image: Option<PathBuf>, tag: String) -> Result<(), WallpaperError> {
+        let image = image.unwrap_or_else(|| self.get_current_image().clone());
+        info!("Untagging {} from {tag}", image.to_string_lossy());
+        if !self.database.untag(&image, &tag)? {
+            return Err(WallpaperError::NotTagged(tag));
+        }
+        if !self.in_collection(&image) {
+            self.images.retain(|other| *other != image);
+        }
+        Ok(())
+    }
+
+    /// Every collection with at least one image
+    pub fn get_collections(&self) -> Result<Vec<Collection>, WallpaperError> {
+        if let Some(e) = &self.scan_error {
+            return Err(e.clone());
+        }
+        let mut counts = BTreeMap::new();
+        for image in self
+            .all_images
+            .iter()
+            .filter(|image| !self.database.is_banned(image))
+        {
+            for tag in self.tags_of(image) {
+                *counts.entry(tag).or_insert(0) += 1;
+            }
+        }
+        Ok(counts
+            .into_iter()
+            .map(|(name, images)| Collection {
+                active: self.collection.as_ref() == Some(&name),
+                name,
+                images,
+            })
+            .collect())
+    }
+
+    /// Switch to the images of a collection, or to all images with `None`
+    ///
+    /// Every collection has its own history, switching back to one shows the
+    /// image it was at.
+    pub fn use_collection(&mut self, name: Option<String>) -> Result<(), WallpaperError> {
+        if self.use_fallback {
+            return Err(WallpaperError::FallbackActive);
+        }
+        if name == self.collection {
+            return Ok(());
+        }
+        if let Some(name) = &name {
+            if !self.get_collections()?.iter().any(|c| c.name == *name) {
+                return Err(WallpaperError::NoSuchCollection(name.clone()));
+            }
+        }
+        info!(
+            "Using collection {}",
+            name.as_deref().unwrap_or("of all images")
+        );
+
+        let current = self.get_current_image().clone();
+        let resumed = self.histories.remove(&name);
+        let resuming = resumed.is_some();
+        let history =
+            resumed.unwrap_or_else(|| History::new(current.clone(), self.history.history_max_size));
+        let history = std::mem::replace(&mut self.history, history);
+        let collection = std::mem::replace(&mut self.collection, name);
+        self.histories.insert(collection, history);
+        self.select_images();
+
+        if let NextImage::Static = self.action {
+            // The static image stays
+            if *self.get_current_image() != current {
+                self.history.push_back(current);
+            }
+            Ok(())
+        } else if resuming {
+            self.update()
+        } else {
+            self.change_image(ChangeImageDirection::Next)
+        }
+    }
+
+    /// Use the rules of another schedule from now on
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule.replace(schedule);
+        self.apply_schedule();
+        // The next transition might be earlier now
+        self.timer.notify_all();
+    }
+
+    /// Apply the schedule rules that started or ended since the last call
+    pub fn apply_schedule(&mut self) {
+        // The fallback image wins, the rules get applied once it's gone
+        if self.use_fallback {
+            return;
+        }
+        let current = Scheduled {
+            collection: self.collection.clone(),
+            mode: self.action,
+            image: match self.action {
+                NextImage::Static => Some(self.get_current_image().clone()),
+                _ => None,
+            },
+            interval: self.change_interval,
+        };
+        let now = Local::now().naive_local();
+        let night = self.schedule.is_night();
+        let settings = match self.schedule.evaluate(now, current.clone()) {
+            Some(settings) => settings,
+            None => return,
+        };
+        info!("Applying the schedule");
+        if let Err(e) = self.apply_scheduled(current, settings) {
+            warn!("Couldn't apply the schedule: {e}");
         }
+        if night != self.schedule.is_night() && self.dark_variants() {
+            info!(
+                "Switching to the {} variant",
+                if night { "light" } else { "dark" }
+            );
+            if let Err(e) = self.update() {
+                warn!("{e}");
+            }
+        }
+    }
+
+    fn dark_variants(&self) -> bool {
+        self.schedule.sun().is_some_and(|sun| sun.dark_variants)
+    }
+
+    /// Dark variants are shown instead of their light image, never on their own
+    fn is_dark_variant(&self, image: &Path) -> bool {
+        self.dark_variants() && light_variant(image).is_some_and(|light| light.is_file())
+    }
+
+    /// The image to hand to the backend for `image`
+    fn displayed_image(&self, image: &Path) -> PathBuf {
+        if self.schedule.is_night() && self.dark_variants() {
+            if let Some(dark) = dark_variant(image).filter(|dark| dark.is_file()) {
+                return dark;
+            }
+        }
+        image.to_path_buf()
+    }
+
+    /// Today's sunrise and sunset
+    pub fn get_sun(&self) -> Result<SunTimes, WallpaperError> {
+        let sun = self.schedule.sun().ok_or(WallpaperError::NoLocation)?;
+        let now = Local::now().naive_local();
+        let events = sun.events(now.date());
+        // Polar days and nights have neither
+        let find = |sunset| {
+            events
+                .iter()
+                .find(|(_, night)| *night == sunset)
+                .map(|(time, _)| time.format("%H:%M").to_string())
+        };
+        Ok(SunTimes {
+            sunrise: find(false),
+            sunset: find(true),
+            night: sun.is_night(now),
+        })
+    }
+
+    fn apply_scheduled(
+        &mut self,
+        current: Scheduled,
+        settings: Scheduled,
+    ) -> Result<(), WallpaperError> {
+        if settings.interval != current.interval {
+            self.change_interval(settings.interval)?;
+        }
+        let mode_changed = settings.mode != current.mode || settings.image != current.image;
+        if mode_changed {
+            self.update_action(settings.mode, settings.image.clone())?;
+        }
+        if settings.collection != current.collection {
+            self.use_collection(settings.collection)
+        } else if mode_changed && current.mode == NextImage::Static {
+            // Don't keep showing the image of static mode
+            match self.change_image(ChangeImageDirection::Next) {
+                Err(WallpaperError::StaticMode) => Ok(()),
+                result => result,
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The next times the schedule changes something
+    pub fn get_transitions(&self) -> Vec<Transition> {
+        self.schedule
+            .transitions(Local::now().naive_local(), TRANSITIONS_LISTED)
+    }
+
+    pub fn get_active_rules(&self) -> Vec<ActiveRule> {
+        self.schedule.active_rules()
+    }
+
+    pub fn get_time_until_transition(&self) -> Option<Duration> {
+        self.schedule
+            .until_next_transition(Local::now().naive_local())
+    }
+
+    /// Whether the patterns allow showing `image` of `source`
+    fn is_allowed(&self, source: &Source, image: &Path) -> bool {
+        image
+            .strip_prefix(&source.dir)
+            .is_ok_and(|relative| self.patterns.allows(relative))
+    }
+
+    pub fn include_pattern(&mut self, pattern: &str) -> Result<(), WallpaperError> {
+        self.patterns.include(pattern)?;
+        self.apply_patterns()
+    }
+
+    pub fn exclude_pattern(&mut self, pattern: &str) -> Result<(), WallpaperError> {
+        self.patterns.exclude(pattern)?;
+        self.apply_patterns()
+    }
+
+    pub fn remove_pattern(&mut self, pattern: &str) -> Result<(), WallpaperError> {
+        self.patterns.remove(pattern)?;
+        self.apply_patterns()
+    }
+
+    pub fn get_filters(&self) -> Filters {
+        self.patterns.filters()
+    }
+
+    /// Rebuild the image list and make sure `previous` can't bring back an
+    /// image the patterns don't allow anymore
+    fn apply_patterns(&mut self) -> Result<(), WallpaperError> {
+        info!("Patterns changed");
+        let current = self.get_current_image().clone();
+        let hidden: HashSet<PathBuf> = self
+            .images
+            .iter()
+            .chain(&self.history.previous)
+            .chain(&self.history.next)
+            .filter(|image| self.hidden(image))
+            .cloned()
+            .collect();
+        let result = if hidden.contains(&current) {
+            // Linear mode carries on after the image, the others must not
+            // pick it again. Without anything else left to pick the default
+            // image gets shown.
+            let keep_current = self.action == NextImage::Linear;
+            self.images
+                .retain(|image| (keep_current && *image == current) || !hidden.contains(image));
+            if self.images.iter().all(|image| *image == current) {
+                self.images.clear();
+            }
+            match self.change_image(ChangeImageDirection::Next) {
+                // The image goes away with the next change
+                Err(WallpaperError::StaticMode | WallpaperError::FallbackActive) => Ok(()),
+                result => result,
+            }
+        } else {
+            Ok(())
+        };
+        self.history.retain(|image| !hidden.contains(image));
+        let _ = self.rescan();
+        result
     }
 
-    pub fn update(&self) -> Result<(), ()> {
+    /// Whether the patterns hide `image`, matched like when scanning the
+    /// sources or reading the playlist
+    fn hidden(&self, image: &Path) -> bool {
+        match &self.playlist {
+            Some(playlist) => {
+                let base = playlist.parent().unwrap_or(Path::new(""));
+                !self
+                    .patterns
+                    .allows(image.strip_prefix(base).unwrap_or(image))
+            }
+            None => self.sources.iter().any(|source| {
+                image
+                    .strip_prefix(&source.dir)
+                    .is_ok_and(|relative| !self.patterns.allows(relative))
+            }),
+        }
+    }
+
+    /// Forget a file that was removed from a source
+    pub fn remove_image(&mut self, path: &Path) {
+        if let Some(idx) = self.all_images.iter().position(|image| image == path) {
+            info!("Image {} is gone", path.to_string_lossy());
+            self.all_images.remove(idx);
+        }
+        self.images.retain(|image| image != path);
+    }
+
+    /// Never show the current image again and move on to the next one
+    pub fn ban(&mut self) -> Result<(), WallpaperError> {
+        let image = self.get_current_image().clone();
+        if image == self.default_image {
+            return Err(WallpaperError::BanDefault);
+        }
+        info!("Banning {}", image.to_string_lossy());
+        self.database.ban(image.clone())?;
+        // Keep the image while the next one is picked so linear mode carries
+        // on after it, unless there is nothing else left to pick
+        if self.images.iter().all(|other| *other == image) {
+            self.remove_image(&image);
+        }
+
+        let result = match self.change_image(ChangeImageDirection::Next) {
+            // The ban is recorded, the image goes away with the next change
+            Err(WallpaperError::StaticMode | WallpaperError::FallbackActive) => Ok(()),
+            result => result,
+        };
+        self.remove_image(&image);
+        self.history.forget(&image);
+        result
+    }
+
+    pub fn unban(&mut self, image: PathBuf) -> Result<(), WallpaperError> {
+        info!("Unbanning {}", image.to_string_lossy());
+        if !self.database.unban(&image)? {
+            return Err(WallpaperError::NotBanned(image));
+        }
+        if image.is_file() {
+            self.add_image(image);
+        }
+        Ok(())
+    }
+
+    pub fn get_banned(&self) -> Vec<PathBuf> {
+        self.database.banned().cloned().collect()
+    }
+
+    pub fn set_sort_order(&mut self, order: SortOrder) {
+        info!("Sorting images by {order}");
+        self.sort_order = order;
+        if self.playlist.is_none() {
+            sort(&mut self.images, order);
+            sort(&mut self.all_images, order);
+        }
+    }
+
+    /// Rating of `image`, or of the current image if it is `None`
+    pub fn get_rating(&self, image: Option<&Path>) -> Rating {
+        self.database
+            .rating(image.unwrap_or_else(|| self.get_current_image()))
+    }
+
+    pub fn rate(&mut self, image: Option<PathBuf>, stars: u8) -> Result<(), WallpaperError> {
+        let image = image.unwrap_or_else(|| self.get_current_image().clone());
+        let mut rating = self.database.rating(&image);
+        info!("Rating {} with {stars} stars", image.to_string_lossy());
+        rating.stars = Some(stars);
+        self.database.set_rating(image, rating)
+    }
+
+    /// Mark `image` as favourite or take the mark away again
+    pub fn toggle_favourite(&mut self, image: Option<PathBuf>) -> Result<Rating, WallpaperError> {
+        let image = image.unwrap_or_else(|| self.get_current_image().clone());
+        let mut rating = self.database.rating(&image);
+        rating.favourite = !rating.favourite;
+        info!(
+            "Setting favourite of {} to {}",
+            image.to_string_lossy(),
+            rating.favourite
+        );
+        self.database.set_rating(image, rating)?;
+        Ok(rating)
+    }
+
+    pub fn get_sort_order(&self) -> SortOrder {
+        self.sort_order
+    }
+
+    pub fn update(&mut self) -> Result<(), WallpaperError> {
         info!("Updating current wallpaper");
-        let path = self.get_current_image();
+        let path = self.displayed_image(self.get_current_image());
         trace!("setting wallpaper to {}", path.to_string_lossy());
         match &self.wallpaper_cmd {
             WallpaperMethod::Feh => {
-                let mut process = if self.recusive {
-                    Command::new("feh")
-                        .arg("-r")
-                        .arg("--bg-fill")
-                        .arg(path)
-                        .spawn()
-                        .unwrap()
-                } else {
-                    Command::new("feh")
-                        .arg("--bg-fill")
-                        .arg(path)
-                        .spawn()
-                        .unwrap()
-                };
-                process.wait().unwrap();
+                let status = Command::new("feh")
+                    .arg("--bg-fill")
+                    .arg(&path)
+                    .status()
+                    .map_err(|e| WallpaperError::Spawn {
+                        program: "feh".to_string(),
+                        message: e.to_string(),
+                    })?;
+                if !status.success() {
+                    return Err(WallpaperError::ExitStatus {
+                        program: "feh".to_string(),
+                        status: status.to_string(),
+                    });
+                }
             }
             WallpaperMethod::Hyprpaper(args) => {
                 // Preload the wallpaper
-                self.send_to_hyprpaper(
-                    format!("preload {}", self.get_current_image().to_string_lossy()).as_bytes(),
-                )?;
+                self.send_to_hyprpaper(format!("preload {}", path.to_string_lossy()).as_bytes())?;
                 // Display the wallpaper on every monitor
                 for monitor in args.monitors.iter() {
                     self.send_to_hyprpaper(
-                        format!(
-                            "wallpaper {monitor},{}",
-                            self.get_current_image().to_string_lossy()
-                        )
-                        .as_bytes(),
+                        format!("wallpaper {monitor},{}", path.to_string_lossy()).as_bytes(),
                     )?;
                 }
 
                 if self.history.previous.len() > 2 {
                     let prev = self.history.previous.iter().rev().nth(2).unwrap();
-                    if prev != self.get_current_image() {
+                    // It was preloaded as the variant shown for it
+                    let prev = self.displayed_image(prev);
+                    if prev != path {
                         self.send_to_hyprpaper(
                             format!("unload {}", prev.to_string_lossy()).as_bytes(),
                         )?;
@@ -221,53 +1168,130 @@ impl State {
                 }
             }
         }
+        self.notify(Event::Wallpaper(path.clone()));
+        // A new image gets the whole interval
+        self.reset_timer();
+        self.persist();
         Ok(())
     }
 
-    fn send_to_hyprpaper(&self, msg: &[u8]) -> Result<String, ()> {
-        let signature = std::env::var("HYPRLAND_INSTANCE_SIGNATURE").map_err(|_| ())?;
+    fn send_to_hyprpaper(&self, msg: &[u8]) -> Result<String, WallpaperError> {
+        let signature = std::env::var("HYPRLAND_INSTANCE_SIGNATURE")
+            .map_err(|_| WallpaperError::NoHyprlandInstance)?;
         let path: PathBuf = ["/tmp/hypr", &signature, ".hyprpaper.sock"]
             .iter()
             .collect();
 
         info!("Connecting to socket at {}", path.to_string_lossy());
 
-        let mut listener = UnixStream::connect(path).map_err(|_| ())?;
-        listener.write_all(msg).map_err(|_| ())?;
+        let hyprpaper_error = |e: std::io::Error| WallpaperError::HyprpaperIpc(e.to_string());
+        let mut listener = UnixStream::connect(path).map_err(hyprpaper_error)?;
+        listener.write_all(msg).map_err(hyprpaper_error)?;
 
-        listener.flush().map_err(|_| ())?;
+        listener.flush().map_err(hyprpaper_error)?;
         let mut buffer = String::new();
-        listener.read_to_string(&mut buffer).map_err(|_| ())?;
+        listener
+            .read_to_string(&mut buffer)
+            .map_err(hyprpaper_error)?;
 
         info!("Got result: {buffer}");
+        if buffer.trim() != "ok" {
+            return Err(WallpaperError::HyprpaperReply(buffer.trim().to_string()));
+        }
         Ok(buffer)
     }
 
-    pub fn update_action(&mut self, action: NextImage, image: Option<PathBuf>) {
+    pub fn update_action(
+        &mut self,
+        action: NextImage,
+        image: Option<PathBuf>,
+    ) -> Result<(), WallpaperError> {
+        if let Some(image) = &image {
+            if !image.is_file() {
+                return Err(WallpaperError::NotAFile(image.clone()));
+            }
+        }
+
         info!("Setting action to {:?}", action);
+        if self.action != action {
+            self.notify(Event::Mode(action));
+        }
         self.action = action;
-        if let Some(image) = image {
-            self.history.push_back(image);
-            if self.update().is_err() {
-                error!("Error setting the wallpaper");
+        match image {
+            Some(image) => {
+                self.history.push_back(image);
+                self.update()
+            }
+            None => {
+                self.persist();
+                Ok(())
             }
         }
     }
 
-    pub fn save(&mut self) {
+    pub fn save(&mut self) -> Result<(), WallpaperError> {
         self.use_fallback = !self.use_fallback;
         info!("Setting fallback to {}", self.use_fallback);
+        self.notify(Event::Fallback(self.use_fallback));
         if self.use_fallback {
             self.previous_action = self.action;
             self.action = NextImage::Static;
             self.history.push_back(self.default_image.clone());
         } else {
             self.action = self.previous_action;
-            self.history.previous.pop_back();
+            // The history must never become empty
+            if self.history.has_previous() {
+                self.history.previous.pop_back();
+            }
         }
-        if self.update().is_err() {
-            error!("Error setting the wallpaper");
+        self.update()
+    }
+
+    pub fn set_default_image(&mut self, image: PathBuf) {
+        info!("Setting the default image to {}", image.to_string_lossy());
+        let showing = (self.use_fallback || self.no_images)
+            && *self.get_current_image() == self.default_image;
+        self.default_image = image.clone();
+        if showing {
+            *self.history.previous.back_mut().unwrap() = image;
+            if let Err(e) = self.update() {
+                warn!("{e}");
+            }
+        }
+    }
+
+    pub fn set_history_length(&mut self, history_max_size: usize) {
+        info!("Setting the history length to {history_max_size}");
+        self.history.resize(history_max_size);
+        for history in self.histories.values_mut() {
+            history.resize(history_max_size);
+        }
+    }
+
+    /// Switch to another program to change the wallpaper with, returns the
+    /// sources the caller has to start watchers for
+    ///
+    /// The sources are replaced, since the images they accept depend on the
+    /// formats the program can display.
+    pub fn set_backend(&mut self, method: WallpaperMethod) -> Vec<Arc<Source>> {
+        info!("Switching to {}", method.name());
+        let supported = method.supported_formats();
+        self.source_defaults.filter.supported = supported.clone();
+        self.sources = self
+            .sources
+            .iter()
+            .map(|source| {
+                let mut source = Source::clone(source);
+                source.filter.supported = supported.clone();
+                Arc::new(source)
+            })
+            .collect();
+        self.wallpaper_cmd = method;
+        let _ = self.rescan();
+        if let Err(e) = self.update() {
+            warn!("{e}");
         }
+        self.get_watched_sources()
     }
 
     pub fn get_current_image(&self) -> &PathBuf {
@@ -278,8 +1302,18 @@ impl State {
         self.action
     }
 
-    pub fn change_interval(&mut self, i: Duration) {
+    pub fn change_interval(&mut self, i: Duration) -> Result<(), WallpaperError> {
+        if i.is_zero() {
+            return Err(WallpaperError::ZeroInterval);
+        }
+        if i > MAX_INTERVAL {
+            return Err(WallpaperError::IntervalTooLong);
+        }
         self.change_interval = i;
+        self.notify(Event::Interval(i));
+        self.reset_timer();
+        self.persist();
+        Ok(())
     }
 
     pub fn get_change_interval(&self) -> Duration {
@@ -289,4 +1323,286 @@ impl State {
     pub fn get_fallback(&self) -> bool {
         self.use_fallback
     }
+
+    /// The condition variable the timer thread waits on, paired with the
+    /// mutex around the state
+    pub fn timer(&self) -> Arc<Condvar> {
+        self.timer.clone()
+    }
+
+    /// Set when the timer thread will change the image next
+    /// Let the timer change the image `after` from now
+    pub fn set_next_change(&mut self, after: Duration) {
+        // Too far in the future to represent is the same as never
+        self.next_change = Instant::now().checked_add(after);
+    }
+
+    /// Start counting down the interval again and let the timer thread know
+    fn reset_timer(&mut self) {
+        match &mut self.paused {
+            Some(remaining) => *remaining = self.change_interval,
+            None => self.set_next_change(self.change_interval),
+        }
+        self.timer.notify_all();
+    }
+
+    /// When the timer thread will change the image next, `None` if paused
+    pub fn get_next_change(&self) -> Option<Instant> {
+        match self.paused {
+            Some(_) => None,
+            None => self.next_change,
+        }
+    }
+
+    /// Stop the timer, the mode stays as it is
+    pub fn pause(&mut self) -> Result<(), WallpaperError> {
+        if self.paused.is_some() {
+            return Err(WallpaperError::AlreadyPaused);
+        }
+        let remaining = self
+            .next_change
+            .map(|at| at.saturating_duration_since(Instant::now()))
+            .unwrap_or(self.change_interval);
+        info!("Pausing with {}s left", remaining.as_secs());
+        self.paused = Some(remaining);
+        self.timer.notify_all();
+        self.notify(Event::Paused(true));
+        self.persist();
+        Ok(())
+    }
+
+    /// Let the timer continue with the time that was left when it was paused
+    pub fn resume(&mut self) -> Result<(), WallpaperError> {
+        let remaining = self.paused.take().ok_or(WallpaperError::NotPaused)?;
+        info!("Resuming with {}s left", remaining.as_secs());
+        self.set_next_change(remaining);
+        self.timer.notify_all();
+        self.notify(Event::Paused(false));
+        self.persist();
+        Ok(())
+    }
+
+    pub fn toggle_pause(&mut self) -> Result<(), WallpaperError> {
+        if self.paused.is_some() {
+            self.resume()
+        } else {
+            self.pause()
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Time until the timer changes the image, `None` if it won't
+    pub fn get_time_until_change(&self) -> Option<Duration> {
+        if self.use_fallback || self.action == NextImage::Static || self.paused.is_some() {
+            return None;
+        }
+        self.next_change
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Get notified about every future change of the state
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Send an event to all subscribers, forgetting the ones that went away
+    fn notify(&mut self, event: Event) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    pub fn get_status(&self) -> Status {
+        if self.use_fallback {
+            Status::Fallback
+        } else if self.action == NextImage::Static {
+            Status::Static
+        } else if self.paused.is_some() {
+            Status::Paused
+        } else if self.no_images {
+            Status::NoImages
+        } else {
+            Status::Rotating
+        }
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            wallpaper: self.get_current_image().clone(),
+            mode: self.action,
+            status: self.get_status(),
+            sort: self.sort_order,
+            interval: self.change_interval,
+            fallback: self.use_fallback,
+            paused: self.paused.is_some(),
+            history_depth: self.history.previous.len() - 1,
+            images: self.images.len(),
+            next_change: self.get_time_until_change(),
+            backend: self.wallpaper_cmd.name().to_string(),
+            playlist: self.playlist.clone(),
+            collection: self.collection.clone(),
+        }
+    }
+}
+
+/// How many transitions `schedule list` shows
+const TRANSITIONS_LISTED: usize = 10;
+
+/// Stars assumed for images that weren't rated
+const DEFAULT_STARS: u8 = 3;
+
+/// Relative chance of an image to be picked in random mode
+fn weight(rating: Rating) -> u32 {
+    let stars = u32::from(rating.stars.unwrap_or(DEFAULT_STARS));
+    if rating.favourite {
+        stars * 2
+    } else {
+        stars
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{ImageFilter, ImageFormat};
+    use crate::scan::ScanOptions;
+    use common::SortKey;
+
+    fn database_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "wallpaperd-state-{}-{name}.json",
+            std::process::id()
+        ))
+    }
+
+    /// A state picking from `images`, with its database in
+    /// [`database_file`]
+    fn state(name: &str, images: &[&str], action: NextImage) -> State {
+        let settings = Settings {
+            change_interval: Duration::from_secs(60),
+            default_image: PathBuf::from("/default.png"),
+            action,
+            explicit_action: true,
+            explicit_interval: true,
+            history_max_size: 10,
+            sort_order: SortOrder {
+                key: SortKey::Name,
+                reverse: false,
+            },
+            source_defaults: SourceDefaults {
+                options: ScanOptions {
+                    recursive: false,
+                    max_depth: None,
+                    hidden: false,
+                },
+                filter: ImageFilter {
+                    extensions: vec!["png".to_string()],
+                    sniff: false,
+                    supported: ImageFormat::ALL.to_vec(),
+                },
+            },
+            patterns: Patterns::default(),
+            playlist: None,
+            schedule: Schedule::default(),
+            state_file: None,
+        };
+        let database = database_file(name);
+        let _ = std::fs::remove_file(&database);
+        let mut state = State::idle(
+            settings,
+            Vec::new(),
+            WallpaperMethod::Feh,
+            Database::load(database),
+        );
+        state.all_images = images.iter().map(PathBuf::from).collect();
+        state.select_images();
+        state
+    }
+
+    /// Picks the next image the way [`State::change_image`] does, without
+    /// showing it
+    fn next(state: &mut State) -> PathBuf {
+        let image = state.pick_next();
+        state.history.push_back(image.clone());
+        image
+    }
+
+    #[test]
+    fn history() {
+        let mut history = History::new(PathBuf::from("a"), 3);
+        assert!(!history.has_previous());
+        for image in ["b", "c", "b", "d"] {
+            history.push_back(PathBuf::from(image));
+        }
+        // The oldest images make room
+        assert_eq!(history.previous, ["c", "b", "d"].map(PathBuf::from));
+
+        history.go_previous();
+        history.go_previous();
+        assert_eq!(history.previous.back(), Some(&PathBuf::from("c")));
+        assert_eq!(history.next, ["d", "b"].map(PathBuf::from));
+        history.go_next();
+        assert_eq!(history.previous.back(), Some(&PathBuf::from("b")));
+
+        // The current image stays
+        history.forget(Path::new("b"));
+        assert_eq!(history.previous, ["c", "b"].map(PathBuf::from));
+        assert_eq!(history.next, ["d"].map(PathBuf::from));
+        history.resize(1);
+        assert_eq!(history.previous, ["b"].map(PathBuf::from));
+    }
+
+    #[test]
+    fn banned_image_is_never_picked() {
+        let mut state = state("ban", &["/a.png", "/b.png", "/c.png"], NextImage::Linear);
+        next(&mut state);
+        let banned = next(&mut state);
+        assert_eq!(banned, Path::new("/b.png"));
+
+        // What `ban` does around changing the image
+        state.database.ban(banned.clone()).unwrap();
+        assert_eq!(next(&mut state), Path::new("/c.png"));
+        state.remove_image(&banned);
+        state.history.forget(&banned);
+
+        state.history.go_previous();
+        assert_eq!(state.get_current_image(), Path::new("/a.png"));
+        for action in [NextImage::Linear, NextImage::Random, NextImage::Shuffle] {
+            state.action = action;
+            for _ in 0..20 {
+                assert_ne!(next(&mut state), banned, "{action:?}");
+            }
+        }
+        // Rescanning doesn't bring it back
+        state.select_images();
+        assert!(!state.images.contains(&banned));
+        let _ = std::fs::remove_file(database_file("ban"));
+    }
+
+    #[test]
+    fn shuffle_cycles() {
+        let images: Vec<_> = (0..8).map(|idx| format!("/{idx}.png")).collect();
+        let images: Vec<_> = images.iter().map(String::as_str).collect();
+        let mut state = state("shuffle", &images, NextImage::Shuffle);
+        for cycle in 0..50 {
+            let before = state.get_current_image().clone();
+            let mut shown: Vec<_> = (0..images.len())
+                .map(|idx| {
+                    // A rescan in the middle of the cycle doesn't start a new one
+                    if idx == 3 {
+                        state.select_images();
+                    }
+                    next(&mut state)
+                })
+                .collect();
+            assert_ne!(shown[0], before, "cycle {cycle}");
+            shown.sort();
+            shown.dedup();
+            assert_eq!(shown.len(), images.len(), "cycle {cycle}");
+        }
+    }
 }
@@ -0,0 +1,233 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Location and what to do with it, as written in the config file
+///
+/// ```toml
+/// [sun]
+/// latitude = 52.52
+/// longitude = 13.40
+/// day = "light"
+/// night = "dark"
+/// dark_variants = true
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Sun {
+    /// Degrees, north is positive
+    latitude: f64,
+    /// Degrees, east is positive
+    longitude: f64,
+    /// Collection to use between sunrise and sunset
+    day: Option<String>,
+    /// Collection to use between sunset and sunrise
+    night: Option<String>,
+    /// Show `foo-dark.png` instead of `foo.png` at night if it exists
+    #[serde(default)]
+    pub dark_variants: bool,
+}
+
+/// Sunrise and sunset of a day in local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Daylight {
+    Normal {
+        sunrise: NaiveDateTime,
+        sunset: NaiveDateTime,
+    },
+    /// The sun doesn't set
+    PolarDay,
+    /// The sun doesn't rise
+    PolarNight,
+}
+
+impl Sun {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            return Err(format!("latitude {} is out of range", self.latitude));
+        }
+        if !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(format!("longitude {} is out of range", self.longitude));
+        }
+        Ok(())
+    }
+
+    /// Collection to use in the day or at night
+    pub fn collection(&self, night: bool) -> Option<&String> {
+        if night {
+            self.night.as_ref()
+        } else {
+            self.day.as_ref()
+        }
+    }
+
+    /// Computes sunrise and sunset with the sunrise equation
+    ///
+    /// Accurate to a minute or two, which is plenty for wallpapers.
+    pub fn daylight(&self, date: NaiveDate) -> Daylight {
+        let j2000 = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+        let days = (date - j2000).num_days() as f64;
+
+        // Mean solar time, anomaly, equation of the center and ecliptic longitude
+        let mean_time = days - self.longitude / 360.0;
+        let anomaly = (357.5291 + 0.98560028 * mean_time).rem_euclid(360.0);
+        let m = anomaly.to_radians();
+        let center = 1.9148 * m.sin() + 0.0200 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+        let ecliptic = (anomaly + center + 180.0 + 102.9372).rem_euclid(360.0);
+        let l = ecliptic.to_radians();
+        let transit = J2000 + mean_time + 0.0053 * m.sin() - 0.0069 * (2.0 * l).sin();
+
+        let declination = (l.sin() * 23.4397_f64.to_radians().sin()).asin();
+        let latitude = self.latitude.to_radians();
+        // The sun's disk and refraction make it visible a bit below the horizon
+        let hour_angle = ((-0.833_f64).to_radians().sin() - latitude.sin() * declination.sin())
+            / (latitude.cos() * declination.cos());
+        if hour_angle > 1.0 {
+            return Daylight::PolarNight;
+        }
+        if hour_angle < -1.0 {
+            return Daylight::PolarDay;
+        }
+        let half_day = hour_angle.acos().to_degrees() / 360.0;
+        match (local(transit - half_day), local(transit + half_day)) {
+            (Some(sunrise), Some(sunset)) => Daylight::Normal { sunrise, sunset },
+            _ => Daylight::PolarDay,
+        }
+    }
+
+    pub fn is_night(&self, now: NaiveDateTime) -> bool {
+        let today = self.daylight(now.date());
+        // The neighbouring days may still have a sunset
+        if today == Daylight::PolarDay {
+            return false;
+        }
+        let mut sun_sets = false;
+        // Far from the time zone's meridian a solar day spills into the
+        // neighbouring local days
+        for date in [
+            now.date().pred_opt(),
+            Some(now.date()),
+            now.date().succ_opt(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Daylight::Normal { sunrise, sunset } = self.daylight(date) {
+                if sunrise <= now && now < sunset {
+                    return false;
+                }
+                sun_sets = true;
+            }
+        }
+        sun_sets || today == Daylight::PolarNight
+    }
+
+    /// Sunsets (`true`) and sunrises (`false`) on `date` in local time, in
+    /// order
+    pub fn events(&self, date: NaiveDate) -> Vec<(NaiveDateTime, bool)> {
+        let mut events: Vec<_> = [date.pred_opt(), Some(date), date.succ_opt()]
+            .into_iter()
+            .flatten()
+            .flat_map(|date| match self.daylight(date) {
+                Daylight::Normal { sunrise, sunset } => vec![(sunrise, false), (sunset, true)],
+                Daylight::PolarDay | Daylight::PolarNight => Vec::new(),
+            })
+            .filter(|(time, _)| time.date() == date)
+            .collect();
+        events.sort();
+        events
+    }
+}
+
+/// Julian date of 2000-01-01 12:00
+const J2000: f64 = 2451545.0;
+/// Julian date of the unix epoch
+const UNIX_EPOCH: f64 = 2440587.5;
+
+fn local(julian_date: f64) -> Option<NaiveDateTime> {
+    let timestamp = ((julian_date - UNIX_EPOCH) * 86400.0).round() as i64;
+    DateTime::from_timestamp(timestamp, 0).map(|time| time.with_timezone(&Local).naive_local())
+}
+
+/// `foo-dark.png` for `foo.png`
+pub fn dark_variant(image: &Path) -> Option<PathBuf> {
+    let stem = image.file_stem()?.to_string_lossy();
+    let mut name = format!("{stem}-dark");
+    if let Some(extension) = image.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    Some(image.with_file_name(name))
+}
+
+/// `foo.png` for `foo-dark.png`
+pub fn light_variant(image: &Path) -> Option<PathBuf> {
+    let stem = image.file_stem()?.to_string_lossy();
+    let mut name = stem.strip_suffix("-dark")?.to_string();
+    if let Some(extension) = image.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    Some(image.with_file_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calendar::tests::date;
+    use chrono::{NaiveTime, TimeZone};
+
+    fn sun(latitude: f64, longitude: f64) -> Sun {
+        Sun {
+            latitude,
+            longitude,
+            day: None,
+            night: None,
+            dark_variants: false,
+        }
+    }
+
+    /// Minutes between a local time and `hh:mm` UTC on the same day
+    fn minutes_off(local: NaiveDateTime, utc: &str) -> i64 {
+        let local = Local.from_local_datetime(&local).unwrap().naive_utc();
+        let utc = local
+            .date()
+            .and_time(NaiveTime::parse_from_str(utc, "%H:%M").unwrap());
+        (local - utc).num_minutes().abs()
+    }
+
+    #[test]
+    fn berlin_midsummer() {
+        // 04:43 and 21:33 in summer time
+        match sun(52.52, 13.405).daylight(date(2024, 6, 21)) {
+            Daylight::Normal { sunrise, sunset } => {
+                assert!(minutes_off(sunrise, "02:43") <= 2, "sunrise at {sunrise}");
+                assert!(minutes_off(sunset, "19:33") <= 2, "sunset at {sunset}");
+            }
+            daylight => panic!("expected a sunrise and sunset, got {daylight:?}"),
+        }
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        let tromso = sun(69.65, 18.96);
+        assert_eq!(tromso.daylight(date(2024, 6, 21)), Daylight::PolarDay);
+        assert_eq!(tromso.daylight(date(2024, 12, 21)), Daylight::PolarNight);
+        assert!(tromso.is_night(date(2024, 12, 21).and_hms_opt(12, 0, 0).unwrap()));
+        assert!(!tromso.is_night(date(2024, 6, 21).and_hms_opt(0, 0, 0).unwrap()));
+        // The first and last days without a sunset border on normal days
+        for day in [date(2024, 5, 18), date(2024, 7, 25)] {
+            assert_eq!(tromso.daylight(day), Daylight::PolarDay);
+            assert!(!tromso.is_night(day.and_hms_opt(11, 0, 0).unwrap()));
+        }
+    }
+
+    #[test]
+    fn dark_variants() {
+        let image = Path::new("/pictures/sea.png");
+        let dark = dark_variant(image).unwrap();
+        assert_eq!(dark, Path::new("/pictures/sea-dark.png"));
+        assert_eq!(light_variant(&dark).unwrap(), image);
+        assert_eq!(light_variant(image), None);
+    }
+}
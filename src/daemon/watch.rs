@@ -0,0 +1,150 @@
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{debug, error, info, warn};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::Duration,
+};
+
+use common::WallpaperError;
+
+use crate::scan::{scan, Source};
+use crate::state::State;
+
+/// How long to wait before trying again if the source can't be watched
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Watched directories of a source
+struct Watcher {
+    inotify: Inotify,
+    directories: HashMap<WatchDescriptor, PathBuf>,
+}
+
+impl Watcher {
+    fn new(source: &Source) -> Result<Self, WallpaperError> {
+        let inotify = Inotify::init().map_err(|e| WallpaperError::io(&source.dir, e))?;
+        let mut directories = HashMap::new();
+        let scanned = scan(&source.dir, &source.options)?;
+
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVE
+            | WatchMask::DELETE_SELF
+            | WatchMask::MOVE_SELF;
+        for dir in scanned.directories {
+            match inotify.watches().add(&dir, mask) {
+                Ok(wd) => {
+                    directories.insert(wd, dir);
+                }
+                Err(e) => warn!("Can't watch {}: {e}", dir.to_string_lossy()),
+            }
+        }
+        debug!("Watching {} directories", directories.len());
+        Ok(Watcher {
+            inotify,
+            directories,
+        })
+    }
+}
+
+// Thread: File system ---> State
+/// Keeps the image list of the state in sync with one source
+///
+/// Returns once the source was removed from the state, which is only noticed
+/// after the next change in its directories.
+pub fn watch(state: Arc<Mutex<State>>, source: Arc<Source>) {
+    let mut buffer = [0; 4096];
+    // The state scans once on its own when the source is added
+    let mut stale = false;
+    let mut retrying = false;
+    loop {
+        if !state.lock().unwrap().has_source(&source) {
+            info!("Stopped watching {}", source.dir.to_string_lossy());
+            return;
+        }
+        let mut watcher = match Watcher::new(&source) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                debug!("Can't watch {}: {e}", source.dir.to_string_lossy());
+                if stale {
+                    let _ = state.lock().unwrap().rescan();
+                    stale = false;
+                }
+                retrying = true;
+                sleep(RETRY_INTERVAL);
+                continue;
+            }
+        };
+        info!("Watching {} for changes", source.dir.to_string_lossy());
+        if stale || retrying {
+            // Catch everything that changed while nobody was watching
+            let _ = state.lock().unwrap().rescan();
+        }
+        retrying = false;
+
+        loop {
+            let events = match watcher.inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Can't watch for new images anymore: {e}");
+                    return;
+                }
+            };
+
+            let mut rescan = false;
+            let mut unlocked = state.lock().unwrap();
+            if !unlocked.has_source(&source) {
+                info!("Stopped watching {}", source.dir.to_string_lossy());
+                return;
+            }
+            for event in events {
+                if event.mask.contains(EventMask::Q_OVERFLOW) {
+                    warn!("Missed some changes in {}", source.dir.to_string_lossy());
+                    rescan = true;
+                    continue;
+                }
+                let dir = match watcher.directories.get(&event.wd) {
+                    Some(dir) => dir,
+                    None => continue,
+                };
+                // A watched directory itself went away
+                if event
+                    .mask
+                    .intersects(EventMask::DELETE_SELF | EventMask::MOVE_SELF)
+                {
+                    rescan = true;
+                    continue;
+                }
+                let path = match event.name {
+                    Some(name) => dir.join(name),
+                    None => continue,
+                };
+
+                if event.mask.contains(EventMask::ISDIR) {
+                    // Subdirectories have to be watched or forgotten
+                    rescan |= source.options.recursive;
+                } else if event
+                    .mask
+                    .intersects(EventMask::CLOSE_WRITE | EventMask::MOVED_TO)
+                    // Symlinks are never written to
+                    || (event.mask.contains(EventMask::CREATE) && path.is_symlink())
+                {
+                    unlocked.add_image(path);
+                } else if event
+                    .mask
+                    .intersects(EventMask::DELETE | EventMask::MOVED_FROM)
+                {
+                    unlocked.remove_image(&path);
+                }
+            }
+
+            if rescan {
+                stale = true;
+                break;
+            }
+        }
+    }
+}